@@ -127,7 +127,7 @@ impl Clocks {
         }
     }
 
-    fn _get_frequency_hfperclk(&self) -> time::Hertz {
+    pub fn get_frequency_hfperclk(&self) -> time::Hertz {
         let hfclk_frequency = self.get_frequency_hfclk();
         let scale = 1 + self.cmu.hfperpresc.read().bits();
 
@@ -137,7 +137,7 @@ impl Clocks {
     fn _get_frequency(&self, clock: Clock) -> time::Hertz {
         match clock {
             Clock::HfClk => self.get_frequency_hfclk(),
-            Clock::HfPerClk => self._get_frequency_hfperclk(),
+            Clock::HfPerClk => self.get_frequency_hfperclk(),
         }
     }
 }
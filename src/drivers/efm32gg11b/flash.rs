@@ -1,11 +1,13 @@
 use core::ops::{Add, Sub};
 
-use bytemuck::cast_slice;
 use efm32gg11b::MSC;
 
 use crate::{
-    hal::flash::ReadWrite,
-    utilities::memory::{IterableByOverlaps, Region},
+    hal::flash::{FlashWriter, ReadWrite, WriteProtect},
+    utilities::{
+        bitwise::SliceBitSubset,
+        memory::{IterableByOverlaps, Region},
+    },
 };
 
 use super::clocks::Clocks;
@@ -14,6 +16,18 @@ pub struct Flash {
     msc: MSC,
 }
 
+/// The User Data page, a single page outside the main array that survives a mass erase.
+pub struct UserData<'a> {
+    flash: &'a mut Flash,
+}
+
+/// The Lock Bits page, which holds the page, mass erase and debug locks.
+///
+/// Lock bits can only be cleared, as this page is only erased by a device erase.
+pub struct LockBits<'a> {
+    flash: &'a mut Flash,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Map;
 
@@ -38,6 +52,8 @@ pub enum Error {
     MemoryIsLocked,
     InvalidAddress,
     MisalignedAccess,
+    EraseNotSupported,
+    UnlockNotSupported,
 }
 
 impl Region<Address> for Map {
@@ -96,11 +112,17 @@ impl Flash {
         while self.msc.status.read().wdataready().bit_is_clear() {}
     }
 
-    fn erase_page(&mut self, page: Page) -> nb::Result<(), Error> {
+    /// Accesses the User Data page as its own `ReadWrite` region.
+    pub fn user_data(&mut self) -> UserData<'_> { UserData { flash: self } }
+
+    /// Accesses the Lock Bits page as its own `ReadWrite` region.
+    pub fn lock_bits(&mut self) -> LockBits<'_> { LockBits { flash: self } }
+
+    fn erase_page(&mut self, page_address: Address) -> nb::Result<(), Error> {
         if self.is_busy() {
             return Err(nb::Error::WouldBlock);
         }
-        self.load_address(page.address())?;
+        self.load_address(page_address)?;
         self.msc.writecmd.write(|w| w.erasepage().set_bit());
         self.wait_until_not_busy();
         Ok(())
@@ -133,27 +155,85 @@ impl Flash {
         }
     }
 
-    fn write_page(&mut self, bytes: &[u8], page: Page) -> nb::Result<(), Error> {
-        if bytes.len() != size::PAGE {
+    /// Programs a word aligned run of bytes, which must not cross a page boundary.
+    fn write_words(&mut self, bytes: &[u8], address: Address) -> nb::Result<(), Error> {
+        let Address(address_value) = address;
+        if address_value & 0b11 != 0 || bytes.len() & 0b11 != 0 {
             return Err(nb::Error::Other(Error::MisalignedAccess));
         }
         if self.is_busy() {
             return Err(nb::Error::WouldBlock);
         }
-        // We know this to be aligned, so it can't fail.
-        let words: &[u32] = cast_slice(bytes);
+        // The slice itself may not be word aligned in RAM, so words are assembled byte by byte.
+        let words = bytes
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
 
-        self.load_address(page.address())?;
+        self.load_address(address)?;
         for word in words {
             self.wait_until_ready_to_write();
             // Safety: Unsafe required to write the entire word at once to a register.
-            unsafe { self.msc.wdata.write(|w| w.bits(*word)) }
+            unsafe { self.msc.wdata.write(|w| w.bits(word)) }
             self.msc.writecmd.write(|w| w.writeonce().set_bit());
             self.wait_until_not_busy();
         }
 
         Ok(())
     }
+
+    /// Writes a block that fits inside the page starting at `page_address`, erasing and
+    /// rewriting the page only when the block can't be written by clearing bits alone.
+    fn write_into_page(
+        &mut self,
+        block: &[u8],
+        page_address: Address,
+        address: Address,
+        erasable: bool,
+    ) -> nb::Result<(), Error> {
+        let page_data = &mut [0u8; size::PAGE];
+        read_raw(page_address, page_data);
+        let offset_into_page = address - page_address;
+        if block.is_subset_of(&page_data[offset_into_page..]) {
+            // No need to erase the page, as we can just flip bits off
+            // (since our block is a bitwise subset of the page)
+            self.write_words(block, address)
+        } else if !erasable {
+            Err(nb::Error::Other(Error::EraseNotSupported))
+        } else {
+            // We have to erase and rewrite any saved data alongside the new block
+            page_data
+                .iter_mut()
+                .skip(offset_into_page)
+                .zip(block)
+                .for_each(|(byte, input)| *byte = *input);
+            nb::block!(self.erase_page(page_address))?;
+            self.write_words(page_data, page_address)
+        }
+    }
+
+    /// Checks that an access is word aligned and falls within a single page.
+    fn check_page_access(
+        page_address: Address,
+        address: Address,
+        length: usize,
+    ) -> nb::Result<(), Error> {
+        if address.0 & 0b11 != 0 || length & 0b11 != 0 {
+            Err(nb::Error::Other(Error::MisalignedAccess))
+        } else if address < page_address || address + length > page_address + size::PAGE {
+            Err(nb::Error::Other(Error::MemoryNotReachable))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn read_raw(address: Address, bytes: &mut [u8]) {
+    let base = address.0 as *const u8;
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        // NOTE(Safety) we are reading directly from raw memory locations,
+        // which is inherently unsafe.
+        *byte = unsafe { *(base.add(index)) };
+    }
 }
 
 impl Drop for Flash {
@@ -181,12 +261,7 @@ impl ReadWrite for Flash {
         if !inside_map {
             Err(nb::Error::Other(Error::MemoryNotReachable))
         } else {
-            let base = address.0 as *const u8;
-            for (index, byte) in bytes.iter_mut().enumerate() {
-                // NOTE(Safety) we are reading directly from raw memory locations,
-                // which is inherently unsafe.
-                *byte = unsafe { *(base.add(index)) };
-            }
+            read_raw(address, bytes);
             Ok(())
         }
     }
@@ -203,16 +278,7 @@ impl ReadWrite for Flash {
         }
 
         for (block, page, address) in Map::pages().overlaps(bytes, address) {
-            let page_data = &mut [0u8; size::PAGE];
-            nb::block!(self.read(page.address(), page_data))?;
-            let offset_into_page = address.0.saturating_sub(page.address().0) as usize;
-            page_data
-                .iter_mut()
-                .skip(offset_into_page)
-                .zip(block)
-                .for_each(|(byte, input)| *byte = *input);
-            nb::block!(self.erase_page(page))?;
-            nb::block!(self.write_page(page_data, page))?;
+            nb::block!(self.write_into_page(block, page.address(), address, true))?;
         }
 
         Ok(())
@@ -246,24 +312,154 @@ impl ReadWrite for Flash {
         address: Self::Address,
         blocks: I,
     ) -> Result<(), Self::Error> {
-        const TRANSFER_SIZE: usize = KB!(64);
-        assert!(TRANSFER_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; TRANSFER_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % TRANSFER_SIZE)..((memory_index % TRANSFER_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % TRANSFER_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - TRANSFER_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
+        // Buffering a page at a time ensures each page is erased at most once.
+        const TRANSFER_SIZE: usize = size::PAGE;
+        FlashWriter::<_, TRANSFER_SIZE>::new(self, address).write_blocks(blocks)
+    }
+}
+
+/// Write protection through the per-page locks in the Lock Bits page, which
+/// take effect after a reset.
+impl WriteProtect for Flash {
+    fn lock_range(&mut self, start: Address, end: Address) -> nb::Result<(), Error> {
+        for page in
+            Map::pages().filter(|page| page.address() < end && page.address() + size::PAGE > start)
+        {
+            self.lock_bits().lock_page(page)?;
+        }
+        Ok(())
+    }
+
+    /// Page locks can only be cleared by a device erase through the debug interface.
+    fn unlock_range(&mut self, _: Address, _: Address) -> nb::Result<(), Error> {
+        Err(nb::Error::Other(Error::UnlockNotSupported))
+    }
+}
+
+impl<'a> UserData<'a> {
+    pub const ADDRESS: Address = Address(address::USER_DATA);
+}
+
+impl<'a> ReadWrite for UserData<'a> {
+    type Error = Error;
+
+    type Address = Address;
+
+    fn label() -> &'static str { "efm32gg11b user data page (Internal)" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let (start, end) = self.range();
+        if address < start || address + bytes.len() > end {
+            Err(nb::Error::Other(Error::MemoryNotReachable))
+        } else {
+            read_raw(address, bytes);
+            Ok(())
+        }
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        Flash::check_page_access(Self::ADDRESS, address, bytes.len())?;
+        self.flash.write_into_page(bytes, Self::ADDRESS, address, true)
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) {
+        (Self::ADDRESS, Self::ADDRESS + size::PAGE)
+    }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> { self.flash.erase_page(Self::ADDRESS) }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        FlashWriter::<_, { size::PAGE }>::new(self, address).write_blocks(blocks)
+    }
+}
+
+impl<'a> LockBits<'a> {
+    pub const ADDRESS: Address = Address(address::LOCK_BITS);
+
+    /// Prevents any further writes or erases to a page of the main array.
+    pub fn lock_page(&mut self, page: Page) -> nb::Result<(), Error> {
+        let word = lock_word::PAGE_LOCKS + page.0 as usize / 32;
+        self.clear_bits(word, 1 << (page.0 % 32))
+    }
+
+    pub fn is_page_locked(&self, page: Page) -> bool {
+        let word = lock_word::PAGE_LOCKS + page.0 as usize / 32;
+        self.word(word) & (1 << (page.0 % 32)) == 0
+    }
+
+    /// Prevents mass erasing both banks of the main array.
+    pub fn lock_mass_erase(&mut self) -> nb::Result<(), Error> {
+        self.clear_bits(lock_word::MASS_ERASE, lock_word::MASS_ERASE_BANKS)
+    }
+
+    pub fn is_mass_erase_locked(&self) -> bool {
+        self.word(lock_word::MASS_ERASE) & lock_word::MASS_ERASE_BANKS
+            != lock_word::MASS_ERASE_BANKS
+    }
+
+    /// Locks out debug access. Only a device erase through the debug interface,
+    /// which also clears the main array, can undo this.
+    pub fn lock_debug_access(&mut self) -> nb::Result<(), Error> {
+        self.clear_bits(lock_word::DEBUG, lock_word::DEBUG_UNLOCKED)
+    }
+
+    pub fn is_debug_locked(&self) -> bool {
+        self.word(lock_word::DEBUG) & lock_word::DEBUG_UNLOCKED != lock_word::DEBUG_UNLOCKED
+    }
+
+    fn word(&self, index: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        read_raw(Self::ADDRESS + index * 4, &mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn clear_bits(&mut self, index: usize, mask: u32) -> nb::Result<(), Error> {
+        let value = self.word(index) & !mask;
+        self.write(Self::ADDRESS + index * 4, &value.to_le_bytes())
+    }
+}
+
+impl<'a> ReadWrite for LockBits<'a> {
+    type Error = Error;
+
+    type Address = Address;
+
+    fn label() -> &'static str { "efm32gg11b lock bits page (Internal)" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let (start, end) = self.range();
+        if address < start || address + bytes.len() > end {
+            Err(nb::Error::Other(Error::MemoryNotReachable))
+        } else {
+            read_raw(address, bytes);
+            Ok(())
         }
-        let remainder = &transfer_array[0..(memory_index % TRANSFER_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))
+    }
+
+    /// Writes to the lock bits page, which only succeeds when it clears bits.
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        Flash::check_page_access(Self::ADDRESS, address, bytes.len())?;
+        self.flash.write_into_page(bytes, Self::ADDRESS, address, false)
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) {
+        (Self::ADDRESS, Self::ADDRESS + size::PAGE)
+    }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        Err(nb::Error::Other(Error::EraseNotSupported))
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        FlashWriter::<_, { size::PAGE }>::new(self, address).write_blocks(blocks)
     }
 }
 
@@ -274,3 +470,38 @@ mod size {
 mod count {
     pub const PAGES: usize = 512;
 }
+
+mod address {
+    pub const USER_DATA: u32 = 0x0FE0_0000;
+    pub const LOCK_BITS: u32 = 0x0FE0_4000;
+}
+
+/// Word indices into the Lock Bits page. A cleared bit means locked.
+mod lock_word {
+    pub const PAGE_LOCKS: usize = 0;
+    pub const MASS_ERASE: usize = 125;
+    pub const MASS_ERASE_BANKS: u32 = 0b11;
+    pub const DEBUG: usize = 127;
+    pub const DEBUG_UNLOCKED: u32 = 0xF;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_words_match_the_reference_manual() {
+        // Lock Bits page layout, as listed in the EFM32GG11 reference manual
+        const PAGE_LOCK_WORD_0: usize = 0;
+        const CONFIGURATION_LOCK_WORD_0: usize = 122;
+        const MASS_ERASE_LOCK_WORD: usize = 125;
+        const DEBUG_LOCK_WORD: usize = 127;
+
+        assert_eq!(PAGE_LOCK_WORD_0, lock_word::PAGE_LOCKS);
+        assert_eq!(MASS_ERASE_LOCK_WORD, lock_word::MASS_ERASE);
+        assert_eq!(DEBUG_LOCK_WORD, lock_word::DEBUG);
+        // Every page has a lock bit, and none of them spill into the lock words above
+        const PAGE_LOCK_WORDS_END: usize = lock_word::PAGE_LOCKS + count::PAGES / 32;
+        const _: () = assert!(PAGE_LOCK_WORDS_END <= CONFIGURATION_LOCK_WORD_0);
+    }
+}
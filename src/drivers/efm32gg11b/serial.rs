@@ -1,21 +1,39 @@
+//! USART/UART implementation.
+//!
+//! # Example
+//! ```ignore
+//! let (tx, rx) = (gpio.pe10.as_output(), gpio.pe11.as_input());
+//! let config = serial::config::Config::default().baudrate(Bps(115_200));
+//! let mut serial = Serial::new(peripherals.USART0, tx, rx, config, &clocks).with_clock::<SysTick>();
+//! ```
 
-use core::any::Any;
+use core::{any::Any, marker::PhantomData};
 
 use super::{clocks::Clocks, gpio::{
     typestate::{Input, Output},
     *,
 }};
-use crate::{serial_write, efm32pac, hal::{gpio::{InputPin, OutputPin}, time::Hertz}};
-use efm32pac::{UART0, UART1, USART0, USART1, USART2, USART3, USART4, USART5};
+use crate::{serial_read, serial_write, efm32pac, hal::{gpio::{InputPin, OutputPin}, serial, time::{Hertz, Milliseconds, Now}}};
+use efm32pac::{CMU, UART0, UART1, USART0, USART1, USART2, USART3, USART4, USART5};
 
 mod sealed {
     use super::*;
-    pub trait RxPin<USART>: InputPin {}
-    pub trait TxPin<USART>: OutputPin {}
+    pub trait RxPin<USART>: InputPin { const LOCATION: u8; }
+    pub trait TxPin<USART>: OutputPin { const LOCATION: u8; }
 }
 use sealed::*;
 
-allowed! {
+/// Like `allowed!`, but numbering each pin with its route location, in list order.
+macro_rules! routable {
+    (@ $function:ident<$serial:ident> [$location:expr]) => {};
+    (@ $function:ident<$serial:ident> [$location:expr] $pin:ident<$mode:ident> $($rest:tt)*) => {
+        impl $function<$serial> for $pin<$mode> { const LOCATION: u8 = $location; }
+        routable!(@ $function<$serial> [$location + 1] $($rest)*);
+    };
+    ($($function:ident<$serial:ident>: [$($pins:tt)+])*) => { $(routable!(@ $function<$serial> [0] $($pins)+);)* };
+}
+
+routable! {
     RxPin<UART0>: [Pf7<Input> Pe1<Input> Pa4<Input> Pc15<Input> Pc5<Input> Pf2<Input> Pe4<Input>]
     RxPin<UART1>: [Pc13<Input> Pf11<Input> Pb10<Input> Pe3<Input> Pe13<Input> Ph12<Input>]
     RxPin<USART0>: [Pe11<Input> Pe6<Input> Pc10<Input> Pe12<Input> Pb8<Input> Pc1<Input> Pg13<Input>]
@@ -35,26 +53,200 @@ allowed! {
     TxPin<USART5>: [Pe8<Output> Pa6<Output> Pf15<Output> Ph10<Output>]
 }
 
-pub struct Serial<U, TX: TxPin<U>, RX: RxPin<U>> {
+/// Serial error
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Framing error
+    Framing,
+    /// RX buffer overflow
+    Overrun,
+    /// Parity check error
+    Parity,
+    /// Timeout error
+    Timeout,
+}
+
+pub mod config {
+    //! Configuration required to construct a new USART/UART instance.
+    use crate::hal::time::{Bps, U32Ext};
+
+    pub enum WordLength {
+        DataBits5,
+        DataBits6,
+        DataBits7,
+        DataBits8,
+    }
+
+    pub enum Parity {
+        ParityNone,
+        ParityEven,
+        ParityOdd,
+    }
+
+    pub enum StopBits {
+        #[doc = "0.5 stop bits"]
+        STOP0P5,
+        #[doc = "1 stop bit"]
+        STOP1,
+        #[doc = "1.5 stop bits"]
+        STOP1P5,
+        #[doc = "2 stop bits"]
+        STOP2,
+    }
+
+    pub struct Config {
+        pub baudrate: Bps,
+        pub wordlength: WordLength,
+        pub parity: Parity,
+        pub stopbits: StopBits,
+    }
+
+    impl Config {
+        pub fn baudrate(mut self, baudrate: Bps) -> Self {
+            self.baudrate = baudrate;
+            self
+        }
+
+        pub fn parity_none(mut self) -> Self {
+            self.parity = Parity::ParityNone;
+            self
+        }
+
+        pub fn parity_even(mut self) -> Self {
+            self.parity = Parity::ParityEven;
+            self
+        }
+
+        pub fn parity_odd(mut self) -> Self {
+            self.parity = Parity::ParityOdd;
+            self
+        }
+
+        pub fn wordlength(mut self, wordlength: WordLength) -> Self {
+            self.wordlength = wordlength;
+            self
+        }
+
+        pub fn stopbits(mut self, stopbits: StopBits) -> Self {
+            self.stopbits = stopbits;
+            self
+        }
+
+        /// Contents of the FRAME register for this configuration.
+        pub(super) fn frame(&self) -> u32 {
+            let databits = match self.wordlength {
+                WordLength::DataBits5 => 2,
+                WordLength::DataBits6 => 3,
+                WordLength::DataBits7 => 4,
+                WordLength::DataBits8 => 5,
+            };
+            let parity = match self.parity {
+                Parity::ParityNone => 0,
+                Parity::ParityEven => 2,
+                Parity::ParityOdd => 3,
+            };
+            let stopbits = match self.stopbits {
+                StopBits::STOP0P5 => 0,
+                StopBits::STOP1 => 1,
+                StopBits::STOP1P5 => 2,
+                StopBits::STOP2 => 3,
+            };
+            databits | (parity << 8) | (stopbits << 12)
+        }
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                baudrate: 115_200_u32.bps(),
+                wordlength: WordLength::DataBits8,
+                parity: Parity::ParityNone,
+                stopbits: StopBits::STOP1,
+            }
+        }
+    }
+}
+
+/// Bit positions in the interrupt flag register
+mod interrupt_flag {
+    pub const RXOF: u32 = 1 << 4;
+    pub const PERR: u32 = 1 << 8;
+    pub const FERR: u32 = 1 << 9;
+    pub const ERRORS: u32 = RXOF | PERR | FERR;
+}
+
+/// Serial abstraction. `CLOCK` is the time base for `TimeoutRead`, and is
+/// set through [`with_clock`](Serial::with_clock).
+pub struct Serial<U, TX: TxPin<U>, RX: RxPin<U>, CLOCK = ()> {
     _tx: TX,
     _rx: RX,
     peripheral: U,
+    _clock: PhantomData<CLOCK>,
 }
 
 impl<U: Any, TX: TxPin<U>, RX: RxPin<U>> Serial<U, TX, RX> {
-    const BAUD_RATE: u32 = 115200;
     const OVERSAMPLE: u32 = 16;
 
-    pub fn new(peripheral: U, tx: TX, rx: RX, clocks: &Clocks) -> Self {
-        let mut serial = Self { peripheral, _tx: tx, _rx: rx };
-        serial.set_baud_rate(clocks);
+    pub fn new(peripheral: U, mut tx: TX, rx: RX, config: config::Config, clocks: &Clocks) -> Self {
+        // Idle high, so the receiver doesn't see a start bit while routing
+        tx.set_high();
+        let mut serial = Self { peripheral, _tx: tx, _rx: rx, _clock: PhantomData };
+        serial.enable_clock();
+
+        // Safety: Unsafe access here is required only to write
+        // multiple bits at once to the same register. We must ensure
+        // that we write bits that leave the peripheral in a known and
+        // correct state.
+        unsafe {
+            // Asynchronous mode, 16x oversampling, dropping frames with parity or framing errors
+            serial_write!(&serial.peripheral, ctrl, |w| { w.skipperrf().set_bit() });
+            serial_write!(&serial.peripheral, frame, |w| { w.bits(config.frame()) });
+        }
+        serial.set_baud_rate(config.baudrate.0, clocks);
+
+        // Safety: As above, route locations are multiple bit fields.
+        unsafe {
+            serial_write!(&serial.peripheral, routeloc0, |w| {
+                w.bits(((TX::LOCATION as u32) << 8) | RX::LOCATION as u32)
+            });
+        }
+        serial_write!(&serial.peripheral, routepen, |w| { w.rxpen().set_bit().txpen().set_bit() });
+        serial_write!(&serial.peripheral, cmd, |w| { w.clearrx().set_bit().cleartx().set_bit() });
+        serial_write!(&serial.peripheral, cmd, |w| { w.rxen().set_bit().txen().set_bit() });
         serial
     }
 
-    fn set_baud_rate(&mut self, clocks: &Clocks) {
-        let Hertz(frequency) = clocks.get_frequency_hfclk();
-        // Operate in u64 to avoid overflow
-        let divider = ((256 * frequency as u64) / ((Self::OVERSAMPLE * Self::BAUD_RATE) - 256) as u64) as u32;
+    /// Uses `CLOCK` to time out blocking reads.
+    pub fn with_clock<CLOCK: Now>(self) -> Serial<U, TX, RX, CLOCK> {
+        let Self { _tx, _rx, peripheral, .. } = self;
+        Serial { _tx, _rx, peripheral, _clock: PhantomData }
+    }
+
+    fn enable_clock(&mut self) {
+        let peripheral = &self.peripheral as &dyn Any;
+        // Safety: Only the enable bit belonging to this peripheral is modified.
+        let cmu = unsafe { &*CMU::ptr() };
+        if peripheral.is::<UART0>() {
+            cmu.hfperclken1.modify(|_, w| w.uart0().set_bit());
+        } else if peripheral.is::<UART1>() {
+            cmu.hfperclken1.modify(|_, w| w.uart1().set_bit());
+        } else if peripheral.is::<USART0>() {
+            cmu.hfperclken0.modify(|_, w| w.usart0().set_bit());
+        } else if peripheral.is::<USART1>() {
+            cmu.hfperclken0.modify(|_, w| w.usart1().set_bit());
+        } else if peripheral.is::<USART2>() {
+            cmu.hfperclken0.modify(|_, w| w.usart2().set_bit());
+        } else if peripheral.is::<USART3>() {
+            cmu.hfperclken0.modify(|_, w| w.usart3().set_bit());
+        } else if peripheral.is::<USART4>() {
+            cmu.hfperclken0.modify(|_, w| w.usart4().set_bit());
+        } else if peripheral.is::<USART5>() {
+            cmu.hfperclken0.modify(|_, w| w.usart5().set_bit());
+        }
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32, clocks: &Clocks) {
+        let divider = clock_divider(clocks.get_frequency_hfperclk(), baud_rate, Self::OVERSAMPLE);
 
         // Safety: Unsafe access here is required only to write
         // multiple bits at once to the same register. We must ensure
@@ -62,7 +254,85 @@ impl<U: Any, TX: TxPin<U>, RX: RxPin<U>> Serial<U, TX, RX> {
         // correct state.
         unsafe { serial_write!(&self.peripheral, clkdiv, |w| { w.bits(divider)}); }
     }
+}
+
+/// Fractional clock divider (in 1/256ths) for an asynchronous baud rate, as
+/// laid out in the CLKDIV register.
+fn clock_divider(Hertz(frequency): Hertz, baud_rate: u32, oversample: u32) -> u32 {
+    const DIVIDER_MASK: u32 = 0x007F_FFF8;
+    // Operate in u64 to avoid overflow, rounding to the nearest 1/32nd
+    let period = (oversample * baud_rate) as u64;
+    let divider = ((32 * frequency as u64 + period / 2) / period).saturating_sub(32) * 8;
+    divider as u32 & DIVIDER_MASK
+}
+
+impl<U: Any, TX: TxPin<U>, RX: RxPin<U>, CLOCK> serial::Read for Serial<U, TX, RX, CLOCK> {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        let flags = serial_read!(&self.peripheral, if_, |r| { r.bits() }) & interrupt_flag::ERRORS;
+        if flags != 0 {
+            // Safety: Writing ones only clears the flags that were read.
+            unsafe { serial_write!(&self.peripheral, ifc, |w| { w.bits(flags) }); }
+        }
+
+        Err(if flags & interrupt_flag::PERR != 0 {
+            nb::Error::Other(Error::Parity)
+        } else if flags & interrupt_flag::FERR != 0 {
+            nb::Error::Other(Error::Framing)
+        } else if flags & interrupt_flag::RXOF != 0 {
+            nb::Error::Other(Error::Overrun)
+        } else if serial_read!(&self.peripheral, status, |r| { r.rxdatav().bit_is_set() }) {
+            return Ok(serial_read!(&self.peripheral, rxdata, |r| { r.rxdata().bits() }));
+        } else {
+            nb::Error::WouldBlock
+        })
+    }
+}
+
+impl<U: Any, TX: TxPin<U>, RX: RxPin<U>, CLOCK: Now> serial::TimeoutRead for Serial<U, TX, RX, CLOCK> {
+    type Error = Error;
+
+    fn read<T: Copy + Into<Milliseconds>>(&mut self, timeout: T) -> Result<u8, Self::Error> {
+        let start = CLOCK::now();
+        while (CLOCK::now() - start) < timeout.into() {
+            match serial::Read::read(self) {
+                Ok(byte) => return Ok(byte),
+                Err(nb::Error::Other(error)) => return Err(error),
+                Err(nb::Error::WouldBlock) => continue,
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+impl<U: Any, TX: TxPin<U>, RX: RxPin<U>, CLOCK> serial::WriteBytes for Serial<U, TX, RX, CLOCK> {
+    type Error = Error;
+
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        if !serial_read!(&self.peripheral, status, |r| { r.txbl().bit_is_set() }) {
+            return Err(nb::Error::WouldBlock);
+        }
+        // Safety: Any 8 bit value is a valid frame.
+        unsafe { serial_write!(&self.peripheral, txdata, |w| { w.txdata().bits(byte) }); }
+        Ok(())
+    }
 
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        if serial_read!(&self.peripheral, status, |r| { r.txc().bit_is_set() }) {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<U: Any, TX: TxPin<U>, RX: RxPin<U>, CLOCK> serial::Write for Serial<U, TX, RX, CLOCK> {
+    type Error = Error;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        serial::WriteBytes::write_all(self, s.as_bytes())
+    }
 }
 
 #[macro_export(local_inner_macros)]
@@ -76,6 +346,19 @@ macro_rules! serial_write_inner {
     };
 }
 
+#[macro_export(local_inner_macros)]
+macro_rules! serial_read_inner {
+    ([$($serial:ident)+] $peripheral:expr, $register_name:ident, |$read:ident| $block:block) => {
+        $(
+            if let Some(p) = ($peripheral as &dyn Any).downcast_ref::<$serial>() {
+                let $read = p.$register_name.read();
+                $block
+            } else
+        )+
+        { core::panic!("Unexpected serial peripheral") }
+    };
+}
+
 #[macro_export(local_inner_macros)]
 macro_rules! serial_write {
     ($peripheral:expr, $register_name:ident, |$write:ident| $block:block) => {
@@ -85,3 +368,32 @@ macro_rules! serial_write {
         );
     };
 }
+
+/// Reads the specific register of a serial peripheral, evaluating to the block's result.
+#[macro_export(local_inner_macros)]
+macro_rules! serial_read {
+    ($peripheral:expr, $register_name:ident, |$read:ident| $block:block) => {
+        serial_read_inner!(
+            [UART0 UART1 USART0 USART1 USART2 USART3 USART4 USART5]
+            $peripheral, $register_name, |$read| $block
+        )
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock_divider_matches_reference_manual_formula() {
+        // Given
+        let frequency = Hertz(72_000_000);
+
+        // When
+        let divider = clock_divider(frequency, 115_200, 16);
+
+        // Then
+        // 72MHz / (16 * (1 + 9744 / 256)) = 115200 baud
+        assert_eq!(divider, 9744);
+    }
+}
@@ -0,0 +1,381 @@
+//! Serial port adapter over the standard library's I/O traits.
+//!
+//! Lets code written against `hal::serial` talk to anything the host can open:
+//! a PTY, a USB serial adapter's device file, or a TCP socket. Useful to run
+//! protocol stacks against real tools (`sx`, `rz`, Python scripts) in CI.
+//!
+//! # Example
+//! ```ignore
+//! let mut serial = HostSerial::open("/dev/pts/3")?;
+//! let length = xmodem::Receiver::new().receive(&mut serial, |payload| file.write_all(payload))?;
+//! ```
+
+use crate::hal::{serial, time::Milliseconds};
+use std::{
+    collections::VecDeque,
+    io,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+/// How long the reader thread waits for data before checking whether the serial was dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The underlying device failed
+    Io(io::ErrorKind),
+    /// Nothing arrived in time
+    Timeout,
+    /// The other end closed the connection
+    Disconnected,
+}
+
+/// Host device that can be read from one thread while being written from another.
+pub trait Device: io::Read + io::Write + Send + Sized + 'static {
+    /// Opens an independent handle to the same device.
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Waits up to `timeout` for the device to become readable, returning whether
+    /// it did. End of stream and errors count as readable, as reads report them.
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool>;
+}
+
+/// Serial port over a host device, such as a PTY, a device file or a TCP socket.
+///
+/// Reads happen on a background thread, through a second handle to the device,
+/// so that they can time out even when the device blocks. The thread waits for
+/// data in short intervals and ends soon after the serial is dropped, closing
+/// its handle.
+pub struct HostSerial<D: Device> {
+    device: D,
+    incoming: Receiver<Result<Vec<u8>, Error>>,
+    pending: VecDeque<u8>,
+    closed: Option<Error>,
+    reader: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self { Error::Io(error.kind()) }
+}
+
+impl<D: Device> HostSerial<D> {
+    /// Serves `device`, reading from a clone of it on a background thread.
+    pub fn new(device: D) -> io::Result<Self> {
+        let mut reader = device.try_clone()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let (sender, incoming) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut buffer = [0u8; 256];
+            while !stopped.load(Ordering::Relaxed) {
+                let result = match reader.wait_readable(POLL_INTERVAL) {
+                    Ok(false) => continue,
+                    Ok(true) => match reader.read(&mut buffer) {
+                        Ok(0) => Err(Error::Disconnected),
+                        Ok(length) => Ok(buffer[..length].to_vec()),
+                        Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(error) => Err(error.into()),
+                    },
+                    Err(error) => Err(error.into()),
+                };
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            device,
+            incoming,
+            pending: VecDeque::new(),
+            closed: None,
+            reader: Some(reader),
+            stop,
+        })
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> nb::Result<u8, Error> {
+        loop {
+            if let Some(byte) = self.pending.pop_front() {
+                return Ok(byte);
+            }
+            if let Some(error) = self.closed {
+                return Err(nb::Error::Other(error));
+            }
+            let chunk = match timeout {
+                Some(timeout) => match self.incoming.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => return Err(nb::Error::Other(Error::Timeout)),
+                    Err(RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+                },
+                None => match self.incoming.try_recv() {
+                    Ok(chunk) => chunk,
+                    Err(TryRecvError::Empty) => return Err(nb::Error::WouldBlock),
+                    Err(TryRecvError::Disconnected) => Err(Error::Disconnected),
+                },
+            };
+            match chunk {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(error) => self.closed = Some(error),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl HostSerial<File> {
+    /// Opens a device file, such as one end of a PTY, for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+}
+
+impl HostSerial<TcpStream> {
+    /// Wraps a connected TCP stream, e.g. to a serial-over-TCP bridge.
+    pub fn from_tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Self::new(stream)
+    }
+}
+
+impl<D: Device> Drop for HostSerial<D> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Device for File {
+    fn try_clone(&self) -> io::Result<Self> { File::try_clone(self) }
+
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut descriptor =
+            libc::pollfd { fd: self.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // NOTE(Safety) Polls a single descriptor, which the file keeps open throughout.
+        match unsafe { libc::poll(&mut descriptor, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => match io::Error::last_os_error() {
+                error if error.kind() == io::ErrorKind::Interrupted => Ok(false),
+                error => Err(error),
+            },
+            ready => Ok(ready > 0),
+        }
+    }
+}
+
+impl Device for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> { TcpStream::try_clone(self) }
+
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.set_read_timeout(Some(timeout))?;
+        match self.peek(&mut [0u8; 1]) {
+            Ok(_) => Ok(true),
+            Err(error)
+                if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Every write is flushed straight away, as protocols wait for a reply right after writing.
+impl<D: Device> serial::WriteBytes for HostSerial<D> {
+    type Error = Error;
+
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.write_all(&[byte]).map_err(nb::Error::Other)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.device.write_all(bytes)?;
+        Ok(self.device.flush()?)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.device.flush().map_err(|error| nb::Error::Other(error.into()))
+    }
+}
+
+impl<D: Device> serial::Write for HostSerial<D> {
+    type Error = Error;
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        serial::WriteBytes::write_all(self, s.as_bytes())
+    }
+}
+
+impl<D: Device> serial::Read for HostSerial<D> {
+    type Error = Error;
+    fn read(&mut self) -> nb::Result<u8, Self::Error> { self.receive(None) }
+}
+
+impl<D: Device> serial::TimeoutRead for HostSerial<D> {
+    type Error = Error;
+
+    fn read<T: Copy + Into<Milliseconds>>(&mut self, timeout: T) -> Result<u8, Self::Error> {
+        let timeout = Duration::from_millis(timeout.into().0 as u64);
+        self.receive(Some(timeout)).map_err(|error| match error {
+            nb::Error::Other(error) => error,
+            nb::Error::WouldBlock => Error::Timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hal::serial::{Read, TimeoutRead},
+        utilities::xmodem,
+    };
+    use std::{ffi::CStr, net::TcpListener, os::unix::io::FromRawFd};
+
+    /// Opens a PTY in raw mode, returning its master end and the path to its slave end.
+    fn pty() -> (File, String) {
+        // NOTE(Safety) Plain libc calls on a descriptor owned by the returned file,
+        // and `ptsname` is only called from here.
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(0, libc::grantpt(master));
+            assert_eq!(0, libc::unlockpt(master));
+            let mut settings = std::mem::zeroed::<libc::termios>();
+            assert_eq!(0, libc::tcgetattr(master, &mut settings));
+            libc::cfmakeraw(&mut settings);
+            assert_eq!(0, libc::tcsetattr(master, libc::TCSANOW, &settings));
+            let path = CStr::from_ptr(libc::ptsname(master)).to_str().unwrap().to_string();
+            (File::from_raw_fd(master), path)
+        }
+    }
+
+    fn tcp_streams() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    fn tcp_pair() -> (HostSerial<TcpStream>, HostSerial<TcpStream>) {
+        let (client, server) = tcp_streams();
+        (HostSerial::from_tcp(client).unwrap(), HostSerial::from_tcp(server).unwrap())
+    }
+
+    #[test]
+    fn reads_time_out_then_report_disconnection() {
+        // Given
+        let (local, mut remote) = tcp_streams();
+        let mut local = HostSerial::from_tcp(local).unwrap();
+
+        // When
+        io::Write::write_all(&mut remote, b"hi").unwrap();
+
+        // Then
+        assert_eq!(Ok(b'h'), TimeoutRead::read(&mut local, Milliseconds(500)));
+        assert_eq!(Ok(b'i'), TimeoutRead::read(&mut local, Milliseconds(500)));
+        assert_eq!(Err(nb::Error::WouldBlock), Read::read(&mut local));
+        assert_eq!(Err(Error::Timeout), TimeoutRead::read(&mut local, Milliseconds(10)));
+
+        // When
+        drop(remote);
+
+        // Then
+        assert_eq!(Err(Error::Disconnected), TimeoutRead::read(&mut local, Milliseconds(500)));
+        assert_eq!(Err(nb::Error::Other(Error::Disconnected)), Read::read(&mut local));
+    }
+
+    #[test]
+    fn dropping_a_tcp_serial_stops_its_reader_and_closes_the_socket() {
+        // Given
+        let (local, mut remote) = tcp_streams();
+        let local = HostSerial::from_tcp(local).unwrap();
+        remote.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        // When
+        drop(local);
+
+        // Then
+        assert_eq!(0, io::Read::read(&mut remote, &mut [0u8; 1]).unwrap());
+    }
+
+    #[test]
+    fn bytes_cross_a_pty_both_ways() {
+        // Given
+        let (mut master, path) = pty();
+        let mut serial = HostSerial::open(path).unwrap();
+
+        // When
+        io::Write::write_all(&mut master, b"ping").unwrap();
+        serial::WriteBytes::write_all(&mut serial, b"pong").unwrap();
+
+        // Then
+        for byte in b"ping" {
+            assert_eq!(Ok(*byte), TimeoutRead::read(&mut serial, Milliseconds(500)));
+        }
+        let mut reply = [0u8; 4];
+        io::Read::read_exact(&mut master, &mut reply).unwrap();
+        assert_eq!(b"pong", &reply);
+    }
+
+    #[test]
+    fn dropping_a_pty_serial_stops_its_reader() {
+        // Given
+        let (_master, path) = pty();
+        let serial = HostSerial::open(path).unwrap();
+        let (done, dropped) = mpsc::channel();
+
+        // When
+        thread::spawn(move || {
+            drop(serial);
+            done.send(()).unwrap();
+        });
+
+        // Then
+        assert_eq!(Ok(()), dropped.recv_timeout(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn xmodem_transfer_over_a_socket() {
+        // Given
+        let data: Vec<u8> = (0..300).map(|i| (i % 256) as u8).collect();
+        let (mut sender_end, mut receiver_end) = tcp_pair();
+        let mut received = vec![];
+
+        // When
+        let (sent, result) = thread::scope(|scope| {
+            let sender = scope.spawn(|| {
+                let mut remaining = &data[..];
+                xmodem::Sender::new().send(&mut sender_end, |buffer| {
+                    let length = buffer.len().min(remaining.len());
+                    buffer[..length].copy_from_slice(&remaining[..length]);
+                    remaining = &remaining[length..];
+                    Ok::<usize, ()>(length)
+                })
+            });
+            let result = xmodem::Receiver::new().receive(&mut receiver_end, |block| {
+                received.extend_from_slice(block);
+                Ok::<(), ()>(())
+            });
+            (sender.join().unwrap(), result)
+        });
+
+        // Then
+        assert_eq!(Ok(data.len()), sent);
+        assert_eq!(Ok(384), result);
+        assert_eq!(&data[..], &received[..data.len()]);
+    }
+}
@@ -1,12 +1,16 @@
 //! Device driver for the [Micron N24q128a](../../../../../../documentation/hardware/micron_flash.pdf#page=0)
 use crate::{
-    hal::{flash::ReadWrite, qspi, time},
+    hal::{
+        flash::{FlashWriter, ReadWrite, Suspend, WriteProtect},
+        qspi, time,
+    },
     utilities::{
         bitwise::{BitFlags, SliceBitSubset},
         memory::{self, IterableByOverlaps, Region},
     },
 };
 use core::{
+    cmp::min,
     marker::PhantomData,
     ops::{Add, Sub},
 };
@@ -134,6 +138,7 @@ where
 {
     qspi: QSPI,
     timeout: Option<time::Milliseconds>,
+    suspended: bool,
     _marker: PhantomData<NOW>,
 }
 
@@ -144,15 +149,22 @@ pub enum Error {
     WrongManufacturerId,
     MisalignedAccess,
     AddressOutOfRange,
+    OperationSuspended,
+    /// Block protection can't express the requested combination of locked sectors.
+    UnsupportedProtection,
 }
 
 #[derive(Debug, Clone, Copy)]
 enum Command {
+    WriteStatus = 0x01,
     PageProgram = 0x02,
     Read = 0x03,
     WriteDisable = 0x04,
     ReadStatus = 0x05,
     WriteEnable = 0x06,
+    ReadFlagStatus = 0x70,
+    ProgramEraseSuspend = 0x75,
+    ProgramEraseResume = 0x7A,
     ReadId = 0x9E,
     BulkErase = 0xC7,
     SectorErase = 0xD8,
@@ -163,6 +175,64 @@ struct Status {
     _write_enable_latch: bool,
 }
 
+/// From [datasheet table 11](../../../../../../../documentation/hardware/micron_flash.pdf#page=25)
+struct FlagStatus {
+    program_erase_controller_ready: bool,
+    _erase_suspended: bool,
+    _program_suspended: bool,
+}
+
+/// Block protection bits in the status register, locking a power of two
+/// number of sectors at the top or bottom of memory. From
+/// [datasheet table 4](../../../../../../../documentation/hardware/micron_flash.pdf#page=16)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockProtection {
+    /// BP3..BP0. Level `n` protects `2^(n - 1)` sectors, level 0 none.
+    level: u8,
+    /// Protects from the bottom (TB bit set) instead of the top.
+    bottom: bool,
+}
+
+impl BlockProtection {
+    const MASK: u8 = 0b0111_1100;
+
+    fn from_status(status: u8) -> Self {
+        let level = ((status >> 2) & 0b111) | (status.is_set(6) as u8) << 3;
+        Self { level, bottom: status.is_set(5) }
+    }
+
+    fn to_status(self) -> u8 {
+        ((self.level & 0b111) << 2) | ((self.level >> 3) & 1) << 6 | (self.bottom as u8) << 5
+    }
+
+    fn sectors(self) -> usize {
+        match self.level {
+            0 => 0,
+            level => min(1 << (level - 1), NUMBER_OF_SECTORS),
+        }
+    }
+
+    /// Smallest level protecting at least `sectors`.
+    fn covering(sectors: usize) -> u8 {
+        match sectors {
+            0 => 0,
+            sectors => sectors.next_power_of_two().trailing_zeros() as u8 + 1,
+        }
+    }
+
+    /// Largest level protecting at most `sectors`.
+    fn within(sectors: usize) -> u8 { (usize::BITS - sectors.leading_zeros()) as u8 }
+
+    /// Index of the first protected sector, and one past the last.
+    fn span(self) -> (usize, usize) {
+        if self.bottom {
+            (0, self.sectors())
+        } else {
+            (NUMBER_OF_SECTORS - self.sectors(), NUMBER_OF_SECTORS)
+        }
+    }
+}
+
 enum CommandData<'a> {
     Read(&'a mut [u8]),
     Write(&'a [u8]),
@@ -178,6 +248,9 @@ where
     type Address = Address;
 
     fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        if self.suspended {
+            return Err(nb::Error::Other(Error::OperationSuspended));
+        }
         // Early yield if flash is not ready for writing
         if Self::status(&mut self.qspi)?.write_in_progress {
             Err(nb::Error::WouldBlock)
@@ -191,6 +264,9 @@ where
     }
 
     fn write(&mut self, address: Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        if self.suspended {
+            return Err(nb::Error::Other(Error::OperationSuspended));
+        }
         if Self::status(&mut self.qspi)?.write_in_progress {
             return Err(nb::Error::WouldBlock);
         }
@@ -226,24 +302,7 @@ where
         address: Self::Address,
         blocks: I,
     ) -> Result<(), Self::Error> {
-        assert!(SECTOR_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; SECTOR_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % SECTOR_SIZE)..((memory_index % SECTOR_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % SECTOR_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - SECTOR_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
-        }
-        let remainder = &transfer_array[0..(memory_index % SECTOR_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))?;
-        Ok(())
+        FlashWriter::<_, SECTOR_SIZE>::new(self, address).write_blocks(blocks)
     }
 
     fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
@@ -263,6 +322,46 @@ where
     fn label() -> &'static str { "Micron n25q128a (External)" }
 }
 
+impl<QSPI, NOW> Suspend for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn suspend(&mut self) -> nb::Result<(), Self::Error> {
+        if !self.suspended {
+            if !Self::status(&mut self.qspi)?.write_in_progress {
+                // Nothing to suspend
+                return Ok(());
+            }
+            Self::execute_command(
+                &mut self.qspi,
+                Command::ProgramEraseSuspend,
+                None,
+                CommandData::None,
+            )?;
+            self.suspended = true;
+        }
+        // Suspension latency is in the order of microseconds, so it's waited out here.
+        self.wait_until_controller_ready()
+    }
+
+    fn resume(&mut self) -> nb::Result<(), Self::Error> {
+        if !self.suspended {
+            return Ok(());
+        }
+        Self::execute_command(
+            &mut self.qspi,
+            Command::ProgramEraseResume,
+            None,
+            CommandData::None,
+        )?;
+        self.suspended = false;
+        Ok(())
+    }
+
+    fn is_suspended(&self) -> bool { self.suspended }
+}
+
 impl<QSPI, NOW> MicronN25q128a<QSPI, NOW>
 where
     QSPI: qspi::Indirect,
@@ -285,6 +384,23 @@ where
         }
     }
 
+    fn wait_until_controller_ready(&mut self) -> nb::Result<(), Error> {
+        if let Some(timeout) = &self.timeout {
+            let start = NOW::now();
+            while !Self::flag_status(&mut self.qspi)?.program_erase_controller_ready {
+                if NOW::now() - start > *timeout {
+                    return Err(nb::Error::Other(Error::TimeOut));
+                }
+            }
+        }
+
+        if Self::flag_status(&mut self.qspi)?.program_erase_controller_ready {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
     // Low level helper for executing Micron commands
     fn execute_command(
         qspi: &mut QSPI,
@@ -330,20 +446,83 @@ where
         })
     }
 
+    fn flag_status(qspi: &mut QSPI) -> nb::Result<FlagStatus, Error> {
+        let mut response = [0u8; 1];
+        Self::execute_command(
+            qspi,
+            Command::ReadFlagStatus,
+            None,
+            CommandData::Read(&mut response),
+        )?;
+        let response = response[0];
+        Ok(FlagStatus {
+            program_erase_controller_ready: response.is_set(7),
+            _erase_suspended: response.is_set(6),
+            _program_suspended: response.is_set(2),
+        })
+    }
+
+    fn status_register(qspi: &mut QSPI) -> nb::Result<u8, Error> {
+        let mut response = [0u8; 1];
+        Self::execute_command(qspi, Command::ReadStatus, None, CommandData::Read(&mut response))?;
+        Ok(response[0])
+    }
+
+    /// Current block protection, along with the rest of the status register.
+    fn block_protection(&mut self) -> nb::Result<(BlockProtection, u8), Error> {
+        let status = Self::status_register(&mut self.qspi)?;
+        if status.is_set(0) {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok((BlockProtection::from_status(status), status))
+        }
+    }
+
+    fn set_block_protection(
+        &mut self,
+        protection: BlockProtection,
+        status: u8,
+    ) -> nb::Result<(), Error> {
+        let status = [(status & !BlockProtection::MASK) | protection.to_status()];
+        Self::execute_command(&mut self.qspi, Command::WriteEnable, None, CommandData::None)?;
+        Self::execute_command(
+            &mut self.qspi,
+            Command::WriteStatus,
+            None,
+            CommandData::Write(&status),
+        )?;
+        while Self::status(&mut self.qspi)?.write_in_progress {}
+        Ok(())
+    }
+
+    /// Sectors in `[start, end)` as a range of sector indices.
+    fn sector_span(start: Address, end: Address) -> (usize, usize) {
+        let first = start.0 as usize / SECTOR_SIZE;
+        let last = (end.0 as usize).saturating_sub(1) / SECTOR_SIZE;
+        (first, min(last + 1, NUMBER_OF_SECTORS))
+    }
+
     /// Blocks until flash ID read checks out, or until timeout
     pub fn new(qspi: QSPI) -> Result<Self, Error> {
-        let mut flash = Self { qspi, timeout: None, _marker: Default::default() };
+        let mut flash = Self { qspi, timeout: None, suspended: false, _marker: Default::default() };
         block!(flash.verify_id())?;
         Ok(flash)
     }
 
     pub fn with_timeout(qspi: QSPI, timeout: time::Milliseconds) -> Result<Self, Error> {
-        let mut flash = Self { qspi, timeout: Some(timeout), _marker: Default::default() };
+        let mut flash =
+            Self { qspi, timeout: Some(timeout), suspended: false, _marker: Default::default() };
         block!(flash.verify_id())?;
         Ok(flash)
     }
 
-    fn erase_sector(&mut self, sector: &Sector) -> nb::Result<(), Error> {
+    /// Starts erasing a sector without waiting for the erase to complete. Until it does,
+    /// reads and writes yield `WouldBlock`, unless the erase is paused through
+    /// [`Suspend::suspend`].
+    pub fn begin_sector_erase(&mut self, sector: &Sector) -> nb::Result<(), Error> {
+        if self.suspended {
+            return Err(nb::Error::Other(Error::OperationSuspended));
+        }
         if Self::status(&mut self.qspi)?.write_in_progress {
             return Err(nb::Error::WouldBlock);
         }
@@ -359,6 +538,11 @@ where
             Some(sector.location()),
             CommandData::None
         ))?;
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, sector: &Sector) -> nb::Result<(), Error> {
+        self.begin_sector_erase(sector)?;
         Ok(block!(self.wait_until_write_complete())?)
     }
 
@@ -386,6 +570,49 @@ where
     }
 }
 
+/// Write protection through the status register block protection bits. As these
+/// lock a single area at the top or bottom of memory, ranges must touch either end,
+/// and are rounded out to a power of two number of sectors.
+impl<QSPI, NOW> WriteProtect for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn lock_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        let (first, last) = Self::sector_span(start, end);
+        if first >= last {
+            return Ok(());
+        }
+        let (current, status) = self.block_protection()?;
+        let (bottom, sectors) = match (first, last) {
+            (0, _) if current.sectors() == 0 || current.bottom => (true, last),
+            (_, NUMBER_OF_SECTORS) if current.sectors() == 0 || !current.bottom => {
+                (false, NUMBER_OF_SECTORS - first)
+            }
+            _ => return Err(nb::Error::Other(Error::UnsupportedProtection)),
+        };
+        let level = BlockProtection::covering(sectors).max(current.level);
+        self.set_block_protection(BlockProtection { level, bottom }, status)
+    }
+
+    /// Shrinks the protected area until it no longer overlaps the range, which may
+    /// unlock more sectors than requested.
+    fn unlock_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        let (first, last) = Self::sector_span(start, end);
+        let (current, status) = self.block_protection()?;
+        let (protected_first, protected_last) = current.span();
+        if first >= last || last <= protected_first || first >= protected_last {
+            return Ok(());
+        }
+        let level = if current.bottom {
+            BlockProtection::within(first)
+        } else {
+            BlockProtection::within(NUMBER_OF_SECTORS - last)
+        };
+        self.set_block_protection(BlockProtection { level, ..current }, status)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -496,6 +723,135 @@ mod test {
         assert!(records[2].contains(&data));
     }
 
+    #[test]
+    fn suspending_and_resuming_a_background_erase() {
+        // Given
+        const BUSY_WRITING_STATUS: u8 = 1;
+        const CONTROLLER_READY_ERASE_SUSPENDED: u8 = 0b1100_0000;
+        let mut flash = flash_to_test();
+        flash.begin_sector_erase(&Sector::at(Address(0x1000)).unwrap()).unwrap();
+        flash.qspi.clear();
+        flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
+        flash.qspi.to_read.push_back(vec![CONTROLLER_READY_ERASE_SUSPENDED]);
+
+        // When
+        flash.suspend().unwrap();
+        let records = &flash.qspi.command_records;
+
+        // Then
+        assert!(flash.is_suspended());
+        assert_eq!(records[0].instruction, Some(Command::ReadStatus as u8));
+        assert_eq!(records[1].instruction, Some(Command::ProgramEraseSuspend as u8));
+        assert_eq!(records[2].instruction, Some(Command::ReadFlagStatus as u8));
+
+        // Then
+        let mut data = [0x00u8; PAGE_SIZE];
+        assert!(flash.read(Address(0x2000), &mut data).is_ok());
+        assert_eq!(
+            flash.write(Address(0x2000), &data),
+            Err(nb::Error::Other(Error::OperationSuspended))
+        );
+
+        // When
+        flash.qspi.clear();
+        flash.resume().unwrap();
+
+        // Then
+        assert!(!flash.is_suspended());
+        assert_eq!(
+            flash.qspi.command_records[0].instruction,
+            Some(Command::ProgramEraseResume as u8)
+        );
+    }
+
+    #[test]
+    fn suspending_waits_for_the_controller_without_blocking() {
+        // Given
+        const BUSY_WRITING_STATUS: u8 = 1;
+        const CONTROLLER_BUSY: u8 = 0b0000_0000;
+        const CONTROLLER_READY_ERASE_SUSPENDED: u8 = 0b1100_0000;
+        let mut flash = flash_to_test();
+        flash.begin_sector_erase(&Sector::at(Address(0x1000)).unwrap()).unwrap();
+        flash.qspi.clear();
+        flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
+        flash.qspi.to_read.push_back(vec![CONTROLLER_BUSY]);
+
+        // When
+        let result = flash.suspend();
+
+        // Then
+        assert_eq!(result, Err(nb::Error::WouldBlock));
+
+        // When
+        flash.qspi.clear();
+        flash.qspi.to_read.push_back(vec![CONTROLLER_READY_ERASE_SUSPENDED]);
+        flash.suspend().unwrap();
+
+        // Then
+        assert!(flash.is_suspended());
+        assert_eq!(flash.qspi.command_records.len(), 1);
+        assert_eq!(flash.qspi.command_records[0].instruction, Some(Command::ReadFlagStatus as u8));
+    }
+
+    #[test]
+    fn suspending_an_idle_flash_does_nothing() {
+        // Given
+        let mut flash = flash_to_test();
+
+        // When
+        flash.suspend().unwrap();
+
+        // Then
+        assert!(!flash.is_suspended());
+        assert_eq!(flash.qspi.command_records.len(), 1);
+        assert_eq!(flash.qspi.command_records[0].instruction, Some(Command::ReadStatus as u8));
+    }
+
+    #[test]
+    fn locking_a_range_rounds_it_out_to_the_bottom_protected_area() {
+        // Given
+        const STATUS_REGISTER_WRITE_DISABLE: u8 = 0b1000_0000;
+        const THREE_SECTORS_FROM_BOTTOM_ROUNDED_TO_FOUR: u8 = 0b1010_1100;
+        let mut flash = flash_to_test();
+        flash.qspi.to_read.push_back(vec![STATUS_REGISTER_WRITE_DISABLE]);
+
+        // When
+        flash.lock_range(Address(0), Address(3 * SECTOR_SIZE as u32 - 1)).unwrap();
+        let records = &flash.qspi.command_records;
+
+        // Then
+        assert_eq!(records[0].instruction, Some(Command::ReadStatus as u8));
+        assert_eq!(records[1].instruction, Some(Command::WriteEnable as u8));
+        assert_eq!(records[2].instruction, Some(Command::WriteStatus as u8));
+        assert!(records[2].contains(&[THREE_SECTORS_FROM_BOTTOM_ROUNDED_TO_FOUR]));
+    }
+
+    #[test]
+    fn unlocking_shrinks_the_protected_area_and_rejects_unsupported_ranges() {
+        // Given
+        const TOP_HALF_PROTECTED: u8 = 0b0100_0000;
+        const TOP_SIXTY_FOUR_SECTORS_PROTECTED: u8 = 0b0001_1100;
+        let mut flash = flash_to_test();
+        flash.qspi.to_read.push_back(vec![TOP_HALF_PROTECTED]);
+
+        // When
+        flash
+            .unlock_range(Address(150 * SECTOR_SIZE as u32), Address(190 * SECTOR_SIZE as u32))
+            .unwrap();
+
+        // Then
+        assert!(flash.qspi.command_records[2].contains(&[TOP_SIXTY_FOUR_SECTORS_PROTECTED]));
+
+        // Given
+        flash.qspi.clear();
+
+        // Then
+        assert_eq!(
+            flash.lock_range(Address(SECTOR_SIZE as u32), Address(2 * SECTOR_SIZE as u32)),
+            Err(nb::Error::Other(Error::UnsupportedProtection))
+        );
+    }
+
     #[test]
     fn subsector_read_command_sequence() {
         // Given
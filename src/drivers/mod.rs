@@ -8,6 +8,7 @@
 #[cfg(feature = "stm32f4_any")]
 #[macro_use]
 pub mod stm32f4 {
+    pub mod dma;
     pub mod flash;
     pub mod gpio;
     #[cfg(any(feature = "stm32f412", feature = "stm32f446"))]
@@ -29,6 +30,12 @@ pub mod efm32gg11b {
     pub mod clocks;
 }
 
+/// Drivers for hosted platforms, built on the standard library.
+#[cfg(not(target_arch = "arm"))]
+pub mod host {
+    pub mod serial;
+}
+
 pub mod led;
 
 /// Drivers for the Micron manufacturer (e.g. external flash).
@@ -0,0 +1,237 @@
+//! DMA stream ownership for the STM32F4 family.
+//!
+//! Each DMA controller is split into its eight streams, which are moved into
+//! the peripherals that use them. As a stream can only be owned once, two
+//! peripherals can't be configured to drive the same stream.
+//!
+//! # Example
+//! ```ignore
+//! let dma2 = peripherals.DMA2.split();
+//! let (tx, rx) = serial.into_dma(dma2.s7, tx_buffer, dma2.s2, rx_buffer);
+//! // ...
+//! let (usart, pins, tx_stream, rx_stream) = rx.release(tx);
+//! ```
+use crate::stm32pac::{dma2, DMA1, DMA2, RCC, USART1, USART2, USART3, USART6};
+use core::marker::PhantomData;
+
+/// Extension trait to split a DMA controller into its streams.
+pub trait DmaExt: Sized {
+    fn split(self) -> Streams<Self>;
+}
+
+/// Exclusive handle to stream `N` of a DMA controller.
+pub struct Stream<DMA, const N: u8> {
+    _dma: PhantomData<DMA>,
+}
+
+/// All streams of a DMA controller.
+pub struct Streams<DMA> {
+    pub s0: Stream<DMA, 0>,
+    pub s1: Stream<DMA, 1>,
+    pub s2: Stream<DMA, 2>,
+    pub s3: Stream<DMA, 3>,
+    pub s4: Stream<DMA, 4>,
+    pub s5: Stream<DMA, 5>,
+    pub s6: Stream<DMA, 6>,
+    pub s7: Stream<DMA, 7>,
+}
+
+/// Transfer direction
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    PeripheralToMemory,
+    MemoryToPeripheral,
+}
+
+/// Bit positions in the stream configuration register (SxCR)
+mod stream_control {
+    pub const EN: u32 = 1 << 0;
+    pub const DIR_MEMORY_TO_PERIPHERAL: u32 = 0b01 << 6;
+    pub const CIRC: u32 = 1 << 8;
+    pub const MINC: u32 = 1 << 10;
+    pub const PL_HIGH: u32 = 0b10 << 16;
+    pub const CHSEL: u32 = 25;
+}
+
+/// Per-stream flags in the interrupt status and flag clear registers
+mod stream_flags {
+    pub const TRANSFER_ERROR: u32 = 1 << 3;
+    pub const TRANSFER_COMPLETE: u32 = 1 << 5;
+    pub const ALL: u32 = 0b11_1101;
+    /// Offset of each stream's flags within its low or high register
+    pub const OFFSETS: [u32; 4] = [0, 6, 16, 22];
+}
+
+/// DMA controller register access. Implemented for DMA1 and DMA2 only.
+pub trait Instance {
+    fn registers() -> &'static dma2::RegisterBlock;
+}
+
+impl Instance for DMA1 {
+    // NOTE(Safety) DMA1 lives at a fixed address for the lifetime of the program.
+    fn registers() -> &'static dma2::RegisterBlock { unsafe { &*DMA1::ptr() } }
+}
+
+impl Instance for DMA2 {
+    // NOTE(Safety) DMA2 lives at a fixed address for the lifetime of the program.
+    fn registers() -> &'static dma2::RegisterBlock { unsafe { &*DMA2::ptr() } }
+}
+
+macro_rules! split_impl {
+    ($($DMAX:ident: $dmaXen:ident,)+) => {
+        $(
+            impl DmaExt for $DMAX {
+                fn split(self) -> Streams<Self> {
+                    // NOTE(Safety) This executes only during initialisation
+                    let rcc = unsafe { &(*RCC::ptr()) };
+                    rcc.ahb1enr.modify(|_, w| w.$dmaXen().set_bit());
+                    Streams {
+                        s0: Stream { _dma: PhantomData },
+                        s1: Stream { _dma: PhantomData },
+                        s2: Stream { _dma: PhantomData },
+                        s3: Stream { _dma: PhantomData },
+                        s4: Stream { _dma: PhantomData },
+                        s5: Stream { _dma: PhantomData },
+                        s6: Stream { _dma: PhantomData },
+                        s7: Stream { _dma: PhantomData },
+                    }
+                }
+            }
+        )+
+    };
+}
+
+split_impl! {
+    DMA1: dma1en,
+    DMA2: dma2en,
+}
+
+/// Operations on an owned stream.
+pub trait StreamControl {
+    /// Stops the stream, waiting for any ongoing transfer to wind down.
+    fn disable(&mut self);
+    fn is_enabled(&self) -> bool;
+    /// Points the stream at a peripheral register and a memory buffer, then starts it.
+    ///
+    /// # Safety
+    ///
+    /// `memory` must stay valid for `length` bytes, and not be accessed in a way
+    /// that conflicts with the transfer direction, until the stream is disabled.
+    unsafe fn start(
+        &mut self,
+        channel: u8,
+        peripheral: u32,
+        memory: u32,
+        length: u16,
+        direction: Direction,
+        circular: bool,
+    );
+    /// Items left to transfer in the current cycle.
+    fn remaining(&self) -> u16;
+    fn is_complete(&self) -> bool;
+    fn has_failed(&self) -> bool;
+    fn clear_flags(&mut self);
+}
+
+impl<DMA: Instance, const N: u8> Stream<DMA, N> {
+    fn stream() -> &'static dma2::ST { &DMA::registers().st[N as usize] }
+
+    fn flags() -> u32 {
+        let registers = DMA::registers();
+        let status =
+            if N < 4 { registers.lisr.read().bits() } else { registers.hisr.read().bits() };
+        (status >> stream_flags::OFFSETS[N as usize % 4]) & stream_flags::ALL
+    }
+}
+
+impl<DMA: Instance, const N: u8> StreamControl for Stream<DMA, N> {
+    fn disable(&mut self) {
+        Self::stream().cr.modify(|_, w| w.en().clear_bit());
+        while self.is_enabled() {}
+    }
+
+    fn is_enabled(&self) -> bool { Self::stream().cr.read().en().bit_is_set() }
+
+    unsafe fn start(
+        &mut self,
+        channel: u8,
+        peripheral: u32,
+        memory: u32,
+        length: u16,
+        direction: Direction,
+        circular: bool,
+    ) {
+        use stream_control::*;
+        self.disable();
+        self.clear_flags();
+        let stream = Self::stream();
+        stream.par.write(|w| w.bits(peripheral));
+        stream.m0ar.write(|w| w.bits(memory));
+        stream.ndtr.write(|w| w.bits(length as u32));
+        // Byte sized items, incrementing through memory only. The FIFO stays in direct mode.
+        let mut control = ((channel as u32) << CHSEL) | PL_HIGH | MINC;
+        if direction == Direction::MemoryToPeripheral {
+            control |= DIR_MEMORY_TO_PERIPHERAL;
+        }
+        if circular {
+            control |= CIRC;
+        }
+        stream.cr.write(|w| w.bits(control));
+        stream.cr.write(|w| w.bits(control | EN));
+    }
+
+    fn remaining(&self) -> u16 { Self::stream().ndtr.read().bits() as u16 }
+
+    fn is_complete(&self) -> bool { Self::flags() & stream_flags::TRANSFER_COMPLETE != 0 }
+
+    fn has_failed(&self) -> bool { Self::flags() & stream_flags::TRANSFER_ERROR != 0 }
+
+    fn clear_flags(&mut self) {
+        let registers = DMA::registers();
+        let flags = stream_flags::ALL << stream_flags::OFFSETS[N as usize % 4];
+        // NOTE(Safety) Writing ones only clears this stream's flags.
+        if N < 4 {
+            registers.lifcr.write(|w| unsafe { w.bits(flags) });
+        } else {
+            registers.hifcr.write(|w| unsafe { w.bits(flags) });
+        }
+    }
+}
+
+/// Sealed trait for all streams that can serve a USART's receiver.
+/// This can't be implemented by the library user: All available
+/// streams should already be implemented internally.
+///
+/// # Safety
+///
+/// `CHANNEL` must route the USART's DMA request to this stream.
+pub unsafe trait RxStream<USART>: StreamControl {
+    const CHANNEL: u8;
+}
+
+/// Sealed trait for all streams that can serve a USART's transmitter.
+/// This can't be implemented by the library user: All available
+/// streams should already be implemented internally.
+///
+/// # Safety
+///
+/// `CHANNEL` must route the USART's DMA request to this stream.
+pub unsafe trait TxStream<USART>: StreamControl {
+    const CHANNEL: u8;
+}
+
+macro_rules! seal_streams { ($function:ident<$USART:ident>: [$($DMA:ident $stream:literal $channel:literal,)+]) => {
+    $(
+        unsafe impl $function<$USART> for Stream<$DMA, $stream> { const CHANNEL: u8 = $channel; }
+    )+
+};}
+
+// Stream and channel mapping, from the DMA request tables in the reference manuals.
+seal_streams!(RxStream<USART1>: [DMA2 2 4, DMA2 5 4,]);
+seal_streams!(TxStream<USART1>: [DMA2 7 4,]);
+seal_streams!(RxStream<USART2>: [DMA1 5 4,]);
+seal_streams!(TxStream<USART2>: [DMA1 6 4,]);
+seal_streams!(RxStream<USART3>: [DMA1 1 4,]);
+seal_streams!(TxStream<USART3>: [DMA1 3 4, DMA1 4 7,]);
+seal_streams!(RxStream<USART6>: [DMA2 1 5, DMA2 2 5,]);
+seal_streams!(TxStream<USART6>: [DMA2 6 5, DMA2 7 5,]);
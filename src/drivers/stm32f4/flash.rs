@@ -1,23 +1,104 @@
 //! Internal Flash controller for the STM32F4 family
 use crate::{
-    hal::flash::ReadWrite,
+    hal::flash::{FlashWriter, ReadWrite, WriteProtect},
     stm32pac::FLASH,
     utilities::{
-        bitwise::SliceBitSubset,
+        bitwise::{BitFlags, SliceBitSubset},
         memory::{self, IterableByOverlaps},
     },
 };
 use core::ops::{Add, Sub};
 use nb::block;
 
-pub struct McuFlash {
+/// Internal flash, with the first `RESERVED_SECTORS` sectors of main memory
+/// reserved for immutable data (e.g. a bootloader image) and therefore out
+/// of the writable range. Use [`sectors_to_reserve`] to size the reserved
+/// region in bytes instead.
+pub struct McuFlash<const RESERVED_SECTORS: usize = DEFAULT_RESERVED_SECTORS> {
     flash: FLASH,
+    /// Sectors of the memory map matching the part's flash layout.
+    sectors: &'static [Sector],
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Reserved sectors unless specified otherwise (64KB in all supported maps).
+pub const DEFAULT_RESERVED_SECTORS: usize = 4;
+
+/// Smallest number of main memory sectors that spans `bytes` from the start
+/// of flash. Sizes are taken from the largest layout of the part, which all
+/// layouts share for at least their first 512KB.
+///
+/// # Example
+/// ```ignore
+/// // Reserves the first three 16KB sectors
+/// let flash = McuFlash::<{ sectors_to_reserve(KB!(48)) }>::reserving(peripherals.FLASH)?;
+/// ```
+pub const fn sectors_to_reserve(bytes: usize) -> usize {
+    let (mut count, mut reserved) = (0, 0usize);
+    while reserved < bytes {
+        reserved += MEMORY_MAP.sectors[count].size;
+        count += 1;
+    }
+    count
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Error {
     MemoryNotReachable,
     MisalignedAccess,
+    /// One-time-programmable block has been locked against further programming
+    OtpBlockLocked,
+    /// One-time-programmable block has already been programmed
+    OtpBlockNotErased,
+    /// Read protection level 2 must be requested explicitly, as it's irreversible
+    IrreversibleOperation,
+    /// Programming crossed a 128-bit row boundary (PGAERR)
+    ProgrammingAlignment,
+    /// Programming size doesn't match the configured parallelism (PGPERR)
+    ProgrammingParallelism,
+    /// Programming or erase attempted out of sequence (PGSERR)
+    ProgrammingSequence,
+    /// Target sector is write protected through the option bytes (WRPERR)
+    WriteProtected,
+    /// Operation could not complete (OPERR)
+    OperationFailed,
+    /// Read attempted from a PCROP protected sector (RDERR)
+    ReadProtected,
+    /// Flash size or bank configuration not covered by any known memory map
+    UnsupportedLayout,
+}
+
+/// Bit positions in the flash status register, from
+/// [section 3.8.4](../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=81)
+mod status {
+    pub const OPERR: u8 = 1;
+    pub const WRPERR: u8 = 4;
+    pub const PGAERR: u8 = 5;
+    pub const PGPERR: u8 = 6;
+    pub const PGSERR: u8 = 7;
+    pub const RDERR: u8 = 8;
+    pub const ERROR_MASK: u32 =
+        (1 << OPERR) | (1 << WRPERR) | (1 << PGAERR) | (1 << PGPERR) | (1 << PGSERR) | (1 << RDERR);
+}
+
+impl Error {
+    /// Decodes the highest priority error flagged in the status register, if any.
+    fn from_status(register: u32) -> Option<Self> {
+        if register.is_set(status::WRPERR) {
+            Some(Error::WriteProtected)
+        } else if register.is_set(status::PGAERR) {
+            Some(Error::ProgrammingAlignment)
+        } else if register.is_set(status::PGPERR) {
+            Some(Error::ProgrammingParallelism)
+        } else if register.is_set(status::PGSERR) {
+            Some(Error::ProgrammingSequence)
+        } else if register.is_set(status::RDERR) {
+            Some(Error::ReadProtected)
+        } else if register.is_set(status::OPERR) {
+            Some(Error::OperationFailed)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Default, Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
@@ -64,14 +145,152 @@ struct Sector {
     block: Block,
     location: Address,
     size: usize,
+    /// Sector number as used by the flash controller, for main memory sectors.
+    number: Option<u8>,
 }
 
 #[non_exhaustive]
-pub struct MemoryMap {
-    sectors: [Sector; SECTOR_NUMBER],
+pub struct MemoryMap<const N: usize = SECTOR_NUMBER> {
+    sectors: [Sector; N],
 }
 
 const UNLOCK_KEYS: [u32; 2] = [0x45670123, 0xCDEF89AB];
+const OPTION_UNLOCK_KEYS: [u32; 2] = [0x08192A3B, 0x4C5D6E7F];
+
+/// Number of blocks in the one-time-programmable area.
+pub const OTP_BLOCK_COUNT: u8 = 16;
+/// Size of each block in the one-time-programmable area.
+pub const OTP_BLOCK_SIZE: usize = 32;
+/// One-time-programmable area layout, from
+/// [section 3.7](../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=78)
+const OTP_BASE: Address = Address(0x1FFF_7800);
+const OTP_LOCK_BASE: Address = Address(0x1FFF_7A00);
+const OTP_LOCKED: u8 = 0x00;
+
+/// Read-out protection level. Lowering the level from 1 to 0 triggers a
+/// mass erase of the main memory.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReadProtection {
+    /// No protection
+    Level0,
+    /// Memory can't be read through the debug interface or from RAM
+    Level1,
+    /// Like level 1, with the debug interface disabled. Irreversible!
+    Level2,
+}
+
+/// Brownout reset threshold
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BrownoutLevel {
+    Off,
+    Level1,
+    Level2,
+    Level3,
+}
+
+/// Per-sector write protection (bit `n` set means sector `n` is protected).
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct WriteProtection(pub u32);
+
+impl WriteProtection {
+    pub fn is_protected(&self, sector_number: u8) -> bool { self.0.is_set(sector_number) }
+    pub fn protect(self, sector_number: u8) -> Self { Self(self.0 | (1 << sector_number)) }
+    pub fn unprotect(self, sector_number: u8) -> Self { Self(self.0 & !(1 << sector_number)) }
+}
+
+/// User option bytes, as mirrored by the FLASH_OPTCR register from
+/// [section 3.6](../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=73)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OptionBytes {
+    pub read_protection: ReadProtection,
+    pub write_protection: WriteProtection,
+    pub brownout_level: BrownoutLevel,
+    /// Independent watchdog enabled by hardware on reset
+    pub hardware_watchdog: bool,
+    /// Reset generated when entering stop mode
+    pub reset_on_stop: bool,
+    /// Reset generated when entering standby mode
+    pub reset_on_standby: bool,
+}
+
+/// Bit positions in the option control register
+mod option_control {
+    pub const OPTLOCK: u8 = 0;
+    pub const OPTSTRT: u8 = 1;
+    pub const BOR_LEV: u32 = 2;
+    pub const WDG_SW: u8 = 5;
+    pub const N_RST_STOP: u8 = 6;
+    pub const N_RST_STDBY: u8 = 7;
+    pub const RDP: u32 = 8;
+    pub const N_WRP: u32 = 16;
+    pub const N_WRP_MASK: u32 = 0xFFF;
+
+    /// Dual bank layout for 1MB parts (f42x/f43x/f469/f479 only)
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    pub const DB1M: u8 = 30;
+
+    pub const RDP_LEVEL_0: u8 = 0xAA;
+    pub const RDP_LEVEL_1: u8 = 0x55;
+    pub const RDP_LEVEL_2: u8 = 0xCC;
+}
+
+impl OptionBytes {
+    fn from_register(register: u32) -> Self {
+        use option_control::*;
+        let read_protection = match (register >> RDP) as u8 {
+            RDP_LEVEL_0 => ReadProtection::Level0,
+            RDP_LEVEL_2 => ReadProtection::Level2,
+            _ => ReadProtection::Level1,
+        };
+        let brownout_level = match (register >> BOR_LEV) & 0b11 {
+            0b11 => BrownoutLevel::Off,
+            0b10 => BrownoutLevel::Level1,
+            0b01 => BrownoutLevel::Level2,
+            _ => BrownoutLevel::Level3,
+        };
+        Self {
+            read_protection,
+            // Sectors are protected when their nWRP bit is *cleared*
+            write_protection: WriteProtection(!(register >> N_WRP) & N_WRP_MASK),
+            brownout_level,
+            hardware_watchdog: register.is_clear(WDG_SW),
+            reset_on_stop: register.is_clear(N_RST_STOP),
+            reset_on_standby: register.is_clear(N_RST_STDBY),
+        }
+    }
+
+    /// Merges the option bytes into an option control register value,
+    /// preserving any bits not covered by them.
+    fn to_register(self, register: u32) -> u32 {
+        use option_control::*;
+        let rdp = match self.read_protection {
+            ReadProtection::Level0 => RDP_LEVEL_0,
+            ReadProtection::Level1 => RDP_LEVEL_1,
+            ReadProtection::Level2 => RDP_LEVEL_2,
+        };
+        let bor = match self.brownout_level {
+            BrownoutLevel::Off => 0b11,
+            BrownoutLevel::Level1 => 0b10,
+            BrownoutLevel::Level2 => 0b01,
+            BrownoutLevel::Level3 => 0b00,
+        };
+        let n_wrp = !self.write_protection.0 & N_WRP_MASK;
+        let preserved = register
+            & !((0b11 << BOR_LEV)
+                | (1 << WDG_SW)
+                | (1 << N_RST_STOP)
+                | (1 << N_RST_STDBY)
+                | (0xFF << RDP)
+                | (N_WRP_MASK << N_WRP));
+        preserved
+            | (bor << BOR_LEV)
+            | ((!self.hardware_watchdog as u32) << WDG_SW)
+            | ((!self.reset_on_stop as u32) << N_RST_STOP)
+            | ((!self.reset_on_standby as u32) << N_RST_STDBY)
+            | ((rdp as u32) << RDP)
+            | (n_wrp << N_WRP)
+    }
+}
 
 #[cfg(feature = "stm32f412")]
 const SECTOR_NUMBER: usize = 15;
@@ -79,13 +298,19 @@ const SECTOR_NUMBER: usize = 15;
 #[cfg(feature = "stm32f446")]
 const SECTOR_NUMBER: usize = 11;
 
+#[cfg(feature = "stm32f407")]
+const SECTOR_NUMBER: usize = 15;
+
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const SECTOR_NUMBER: usize = 28;
+
 #[cfg(feature = "stm32f412")]
 const MEMORY_MAP: MemoryMap = MemoryMap {
     sectors: [
-        Sector::new(Block::Reserved, Address(0x0800_0000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_4000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_8000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
         Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
         Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
         Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
@@ -98,15 +323,16 @@ const MEMORY_MAP: MemoryMap = MemoryMap {
         Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
         Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
     ],
-};
+}
+.numbered();
 
 #[cfg(feature = "stm32f446")]
 const MEMORY_MAP: MemoryMap = MemoryMap {
     sectors: [
-        Sector::new(Block::Reserved, Address(0x0800_0000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_4000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_8000), KB!(16)),
-        Sector::new(Block::Reserved, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
         Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
         Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
         Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
@@ -115,7 +341,143 @@ const MEMORY_MAP: MemoryMap = MemoryMap {
         Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
         Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
     ],
-};
+}
+.numbered();
+
+#[cfg(feature = "stm32f407")]
+const MEMORY_MAP: MemoryMap = MemoryMap {
+    sectors: [
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0806_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0808_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080A_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080C_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080E_0000), KB!(128)),
+        Sector::new(Block::SystemMemory, Address(0x1FFF_0000), KB!(30)),
+        Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
+        Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
+    ],
+}
+.numbered();
+
+/// Dual bank layout of the 2MB parts, from
+/// [table 6](../../../../../../../documentation/hardware/stm32f429_reference.pdf#page=77).
+/// Sectors 12 to 23 form the second bank. 1MB parts use [`SINGLE_BANK_1MB_MAP`],
+/// or [`DUAL_BANK_1MB_MAP`] with the DB1M option set.
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const MEMORY_MAP: MemoryMap = MemoryMap {
+    sectors: [
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0806_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0808_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080A_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080C_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080E_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0810_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0810_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0810_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0810_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0811_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x0812_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0814_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0816_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0818_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x081A_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x081C_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x081E_0000), KB!(128)),
+        Sector::new(Block::OptionBytes, Address(0x1FFE_C000), 16),
+        Sector::new(Block::SystemMemory, Address(0x1FFF_0000), KB!(30)),
+        Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
+        Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
+    ],
+}
+.numbered();
+
+/// Single bank layout of the 1MB parts, from
+/// [table 5](../../../../../../../documentation/hardware/stm32f429_reference.pdf).
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const SINGLE_BANK_1MB_MAP: MemoryMap<16> = MemoryMap {
+    sectors: [
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0806_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0808_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080A_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080C_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080E_0000), KB!(128)),
+        Sector::new(Block::OptionBytes, Address(0x1FFE_C000), 16),
+        Sector::new(Block::SystemMemory, Address(0x1FFF_0000), KB!(30)),
+        Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
+        Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
+    ],
+}
+.numbered();
+
+/// Dual bank layout of the 1MB parts with the DB1M option set, from
+/// [table 7](../../../../../../../documentation/hardware/stm32f429_reference.pdf).
+/// Each bank holds 512KB, and the second one is numbered from sector 12.
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const DUAL_BANK_1MB_MAP: MemoryMap<20> = MemoryMap {
+    sectors: [
+        Sector::new(Block::Main, Address(0x0800_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0800_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0801_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x0802_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0804_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0806_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x0808_0000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0808_4000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0808_8000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0808_C000), KB!(16)),
+        Sector::new(Block::Main, Address(0x0809_0000), KB!(64)),
+        Sector::new(Block::Main, Address(0x080A_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080C_0000), KB!(128)),
+        Sector::new(Block::Main, Address(0x080E_0000), KB!(128)),
+        Sector::new(Block::OptionBytes, Address(0x1FFE_C000), 16),
+        Sector::new(Block::SystemMemory, Address(0x1FFF_0000), KB!(30)),
+        Sector::new(Block::OneTimeProgrammable, Address(0x1FFF_7800), 528),
+        Sector::new(Block::OptionBytes, Address(0x1FFF_C000), 16),
+    ],
+}
+.numbered()
+.second_bank_from(Address(0x0808_0000));
+
+/// First sector of the second bank in dual bank parts.
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const SECOND_BANK_FIRST_SECTOR: u8 = 12;
+
+/// Flash size in KB, part of the device electronic signature.
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+const FLASH_SIZE_REGISTER: *const u16 = 0x1FFF_7A22 as *const u16;
+
+/// Flash memory bank of the dual bank parts.
+#[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bank {
+    /// Sectors 0 to 11
+    First,
+    /// Sectors from 12 onwards (up to 23, or 19 in 1MB parts)
+    Second,
+}
 
 const fn max_sector_size() -> usize {
     let (mut index, mut size) = (0, 0usize);
@@ -130,63 +492,110 @@ const fn max_sector_size() -> usize {
 }
 
 impl MemoryMap {
-    // Verifies that the memory map is consecutive and well formed
-    fn is_sound(&self) -> bool {
-        let main_sectors = self.sectors.iter().filter(|s| s.is_in_main_memory_area());
-        let mut consecutive_pairs = main_sectors.clone().zip(main_sectors.skip(1));
-        let consecutive = consecutive_pairs.all(|(a, b)| a.end() == b.start());
-        let ranges_valid =
-            self.sectors.iter().map(|s| Range(s.start(), s.end())).all(Range::is_valid);
-        consecutive && ranges_valid
-    }
+    /// Memory map with its first `count` main memory sectors reserved.
+    const fn reserving(count: usize) -> Self { MEMORY_MAP.reserve(count) }
+}
 
-    fn sectors() -> impl Iterator<Item = Sector> { MEMORY_MAP.sectors.iter().cloned() }
-    pub const fn writable_start() -> Address {
+impl<const N: usize> MemoryMap<N> {
+    /// Copy of this map with its first `count` main memory sectors reserved.
+    const fn reserve(&self, count: usize) -> Self {
+        let mut map = MemoryMap { sectors: self.sectors };
         let mut i = 0;
-        loop {
-            if MEMORY_MAP.sectors[i].is_writable() {
-                break MEMORY_MAP.sectors[i].start();
+        while i < count && i < N {
+            if map.sectors[i].is_in_main_memory_area() {
+                map.sectors[i].block = Block::Reserved;
             }
             i += 1;
         }
+        map
     }
-    pub const fn writable_end() -> Address {
-        let mut i = 0;
-        loop {
-            // Reach the writable area.
-            if MEMORY_MAP.sectors[i].is_writable() {
-                break;
+
+    /// Numbers the main memory sectors in order.
+    const fn numbered(mut self) -> Self {
+        let (mut i, mut number) = (0, 0);
+        while i < N {
+            if self.sectors[i].is_in_main_memory_area() {
+                self.sectors[i].number = Some(number);
+                number += 1;
             }
             i += 1;
         }
+        self
+    }
 
-        loop {
-            // Reach the end of the writable area
-            if !MEMORY_MAP.sectors[i + 1].is_writable() {
-                break MEMORY_MAP.sectors[i].end();
+    /// Renumbers the main memory sectors from `location` onwards as the second bank.
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    const fn second_bank_from(mut self, location: Address) -> Self {
+        let (mut i, mut number) = (0, SECOND_BANK_FIRST_SECTOR);
+        while i < N {
+            if self.sectors[i].is_in_main_memory_area() && self.sectors[i].location.0 >= location.0
+            {
+                self.sectors[i].number = Some(number);
+                number += 1;
             }
             i += 1;
         }
+        self
+    }
+
+    // Verifies that the memory map is consecutive and well formed,
+    // and that reservations leave some writable memory
+    fn is_sound(&self) -> bool {
+        let main_sectors = self.sectors.iter().filter(|s| s.is_in_main_memory_area());
+        let mut consecutive_pairs = main_sectors.clone().zip(main_sectors.skip(1));
+        let consecutive = consecutive_pairs.all(|(a, b)| a.end() == b.start());
+        let ranges_valid =
+            self.sectors.iter().map(|s| Range(s.start(), s.end())).all(Range::is_valid);
+        let any_writable = self.sectors.iter().any(Sector::is_writable);
+        consecutive && ranges_valid && any_writable
+    }
+
+    pub const fn writable_start(&self) -> Address { writable_start(&self.sectors) }
+    pub const fn writable_end(&self) -> Address { writable_end(&self.sectors) }
+}
+
+const fn writable_start(sectors: &[Sector]) -> Address {
+    let mut i = 0;
+    loop {
+        if sectors[i].is_writable() {
+            break sectors[i].start();
+        }
+        i += 1;
+    }
+}
+
+const fn writable_end(sectors: &[Sector]) -> Address {
+    let mut i = 0;
+    loop {
+        // Reach the writable area.
+        if sectors[i].is_writable() {
+            break;
+        }
+        i += 1;
+    }
+
+    loop {
+        // Reach the end of the writable area
+        if !sectors[i + 1].is_writable() {
+            break sectors[i].end();
+        }
+        i += 1;
     }
 }
 
 impl Range {
     /// Sectors spanned by this range of addresses
-    fn span(self) -> &'static [Sector] {
-        let first = MEMORY_MAP
-            .sectors
-            .iter()
-            .enumerate()
-            .find_map(|(i, sector)| self.overlaps(sector).then_some(i));
-        let last = MEMORY_MAP
-            .sectors
+    fn span(self, sectors: &'static [Sector]) -> &'static [Sector] {
+        let first =
+            sectors.iter().enumerate().find_map(|(i, sector)| self.overlaps(sector).then_some(i));
+        let last = sectors
             .iter()
             .enumerate()
             .rev()
             .find_map(|(i, sector)| self.overlaps(sector).then_some(i));
         match (first, last) {
-            (Some(first), Some(last)) if (last >= first) => &MEMORY_MAP.sectors[first..(last + 1)],
-            _ => &MEMORY_MAP.sectors[0..1],
+            (Some(first), Some(last)) if (last >= first) => &sectors[first..(last + 1)],
+            _ => &sectors[0..1],
         }
     }
 
@@ -206,7 +615,9 @@ impl Range {
     }
 
     /// Verify that all sectors spanned by this range are writable
-    fn is_writable(self) -> bool { self.span().iter().all(Sector::is_writable) }
+    fn is_writable(self, sectors: &'static [Sector]) -> bool {
+        self.span(sectors).iter().all(Sector::is_writable)
+    }
 }
 
 impl memory::Region<Address> for Sector {
@@ -219,23 +630,95 @@ impl Sector {
     const fn start(&self) -> Address { self.location }
     const fn end(&self) -> Address { Address(self.start().0 + self.size as u32) }
     const fn new(block: Block, location: Address, size: usize) -> Self {
-        Sector { block, location, size }
+        Sector { block, location, size, number: None }
+    }
+    /// Value for the sector selection (SNB) field when erasing this sector.
+    fn selection(&self) -> Option<u8> {
+        let number = self.number()?;
+        // In dual bank parts, the second bank's sectors are selected from 0b10000
+        #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+        let number = if number >= SECOND_BANK_FIRST_SECTOR {
+            0b1_0000 | (number - SECOND_BANK_FIRST_SECTOR)
+        } else {
+            number
+        };
+        Some(number)
     }
-    fn number(&self) -> Option<u8> {
-        MEMORY_MAP.sectors.iter().enumerate().find_map(|(index, sector)| {
-            (sector.is_in_main_memory_area() && self == sector).then_some(index as u8)
-        })
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    fn bank(&self) -> Option<Bank> {
+        let number = self.number()?;
+        Some(if number < SECOND_BANK_FIRST_SECTOR { Bank::First } else { Bank::Second })
     }
+    fn number(&self) -> Option<u8> { self.number }
     const fn is_writable(&self) -> bool { self.block as u8 == Block::Main as u8 }
+    fn one_time_programmable() -> Self {
+        MEMORY_MAP.sectors.iter().copied().find(|s| s.block == Block::OneTimeProgrammable).unwrap()
+    }
     const fn is_in_main_memory_area(&self) -> bool {
         self.block as u8 == Block::Main as u8 || self.block as u8 == Block::Reserved as u8
     }
 }
 
 impl McuFlash {
-    pub fn new(flash: FLASH) -> Result<Self, Error> {
-        assert!(MEMORY_MAP.is_sound());
-        Ok(Self { flash })
+    /// Internal flash with the default reservation, [`DEFAULT_RESERVED_SECTORS`].
+    pub fn new(flash: FLASH) -> Result<Self, Error> { Self::reserving(flash) }
+}
+
+impl<const RESERVED_SECTORS: usize> McuFlash<RESERVED_SECTORS> {
+    const MEMORY_MAP: MemoryMap = MemoryMap::reserving(RESERVED_SECTORS);
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    const SINGLE_BANK_1MB_MAP: MemoryMap<16> = SINGLE_BANK_1MB_MAP.reserve(RESERVED_SECTORS);
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    const DUAL_BANK_1MB_MAP: MemoryMap<20> = DUAL_BANK_1MB_MAP.reserve(RESERVED_SECTORS);
+
+    /// Internal flash with the first `RESERVED_SECTORS` sectors reserved.
+    ///
+    /// Parts with more than one flash layout (f429 and f469) are identified
+    /// from their flash size and DB1M option, and fail with
+    /// [`Error::UnsupportedLayout`] if none of the known maps applies.
+    pub fn reserving(flash: FLASH) -> Result<Self, Error> {
+        #[cfg(not(any(feature = "stm32f429", feature = "stm32f469")))]
+        let (sound, sectors) = (Self::MEMORY_MAP.is_sound(), &Self::MEMORY_MAP.sectors[..]);
+        #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+        let (sound, sectors) = {
+            // NOTE(Safety): The flash size register is always readable.
+            let size_kb = unsafe { core::ptr::read_volatile(FLASH_SIZE_REGISTER) };
+            let dual_bank_1mb = flash.optcr.read().bits().is_set(option_control::DB1M);
+            match (size_kb, dual_bank_1mb) {
+                (2048, _) => (Self::MEMORY_MAP.is_sound(), &Self::MEMORY_MAP.sectors[..]),
+                (1024, false) => {
+                    (Self::SINGLE_BANK_1MB_MAP.is_sound(), &Self::SINGLE_BANK_1MB_MAP.sectors[..])
+                }
+                (1024, true) => {
+                    (Self::DUAL_BANK_1MB_MAP.is_sound(), &Self::DUAL_BANK_1MB_MAP.sectors[..])
+                }
+                _ => return Err(Error::UnsupportedLayout),
+            }
+        };
+        assert!(sound);
+        Ok(Self { flash, sectors })
+    }
+
+    /// Erases a whole bank in a single operation (MER/MER1), which is much
+    /// faster than erasing its sectors one by one. Banks holding reserved
+    /// sectors can't be erased this way.
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    pub fn erase_bank(&mut self, bank: Bank) -> nb::Result<(), Error> {
+        let mut sectors =
+            self.sectors.iter().filter(|sector| sector.bank() == Some(bank)).peekable();
+        if sectors.peek().is_none() || !sectors.all(Sector::is_writable) {
+            return Err(nb::Error::Other(Error::MemoryNotReachable));
+        }
+        self.unlock()?;
+        match bank {
+            Bank::First => self.flash.cr.modify(|_, w| w.mer().set_bit()),
+            Bank::Second => self.flash.cr.modify(|_, w| w.mer1().set_bit()),
+        }
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait_until_complete();
+        self.flash.cr.modify(|_, w| w.mer().clear_bit().mer1().clear_bit());
+        self.lock();
+        Ok(result?)
     }
 
     /// Parallelism for 3v3 voltage from [table 7](../../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=63)
@@ -249,23 +732,188 @@ impl McuFlash {
         self.flash.keyr.write(|w| unsafe { w.bits(UNLOCK_KEYS[0]) });
         self.flash.keyr.write(|w| unsafe { w.bits(UNLOCK_KEYS[1]) });
         self.flash.cr.modify(|_, w| unsafe { w.psize().bits(0b10) });
+        // Stale flags from a previous operation would otherwise be
+        // reported as failures of the next one.
+        self.clear_errors();
         Ok(())
     }
 
     fn lock(&mut self) { self.flash.cr.modify(|_, w| w.lock().set_bit()); }
 
+    /// Reads one of the blocks in the one-time-programmable area.
+    pub fn read_otp_block(&self, block: u8) -> Result<[u8; OTP_BLOCK_SIZE], Error> {
+        let mut bytes = [0u8; OTP_BLOCK_SIZE];
+        Self::read_raw(otp_block_address(block)?, &mut bytes);
+        Ok(bytes)
+    }
+
+    pub fn is_otp_block_locked(&self, block: u8) -> Result<bool, Error> {
+        let mut lock = [0u8; 1];
+        Self::read_raw(otp_lock_address(block)?, &mut lock);
+        Ok(lock[0] == OTP_LOCKED)
+    }
+
+    /// Programs a block in the one-time-programmable area. Only blocks
+    /// that are unlocked and still erased can be programmed.
+    pub fn program_otp_block(
+        &mut self,
+        block: u8,
+        bytes: &[u8; OTP_BLOCK_SIZE],
+    ) -> nb::Result<(), Error> {
+        let address = otp_block_address(block)?;
+        if self.is_otp_block_locked(block)? {
+            return Err(nb::Error::Other(Error::OtpBlockLocked));
+        }
+        if self.read_otp_block(block)?.iter().any(|b| *b != 0xFF) {
+            return Err(nb::Error::Other(Error::OtpBlockNotErased));
+        }
+        self.write_bytes(bytes, &Sector::one_time_programmable(), address)
+    }
+
+    /// Permanently locks a block in the one-time-programmable area.
+    pub fn lock_otp_block(&mut self, block: u8) -> nb::Result<(), Error> {
+        let lock_address = otp_lock_address(block)?;
+        if self.is_otp_block_locked(block)? {
+            return Ok(());
+        }
+        // Neighbouring lock bytes may already be programmed, and programmed
+        // flash can't be written again. Only the lock byte itself is
+        // programmed, so parallelism drops to a byte for this operation.
+        block!(self.unlock())?;
+        self.flash.cr.modify(|_, w| unsafe { w.psize().bits(0b00).pg().set_bit() });
+        // NOTE(Safety): The lock byte address is checked to be within the OTP area.
+        unsafe { core::ptr::write_volatile(lock_address.0 as *mut u8, OTP_LOCKED) };
+        let result = self.wait_until_complete();
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        Ok(result?)
+    }
+
+    /// Currently loaded option bytes.
+    pub fn option_bytes(&self) -> OptionBytes {
+        #[allow(unused_mut)]
+        let mut option_bytes = OptionBytes::from_register(self.flash.optcr.read().bits());
+        // Second bank write protection lives in its own register
+        #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+        {
+            let second_bank = OptionBytes::from_register(self.flash.optcr1.read().bits());
+            option_bytes.write_protection.0 |=
+                second_bank.write_protection.0 << SECOND_BANK_FIRST_SECTOR;
+        }
+        option_bytes
+    }
+
+    /// Programs the user option bytes. Changes to read protection only take
+    /// effect after a reset.
+    ///
+    /// Refuses to enable read protection level 2, as it permanently disables
+    /// debugging and further option byte changes. Use
+    /// [`enable_permanent_read_protection`](Self::enable_permanent_read_protection)
+    /// for that.
+    pub fn program_option_bytes(&mut self, option_bytes: &OptionBytes) -> nb::Result<(), Error> {
+        if option_bytes.read_protection == ReadProtection::Level2 {
+            return Err(nb::Error::Other(Error::IrreversibleOperation));
+        }
+        self.write_option_control(option_bytes)
+    }
+
+    /// Enables read protection level 2, keeping all other option bytes as they are.
+    ///
+    /// # Warning
+    ///
+    /// This is **irreversible**. The debug interface is disabled for good,
+    /// and the option bytes can never be changed again.
+    pub fn enable_permanent_read_protection(&mut self) -> nb::Result<(), Error> {
+        let option_bytes =
+            OptionBytes { read_protection: ReadProtection::Level2, ..self.option_bytes() };
+        self.write_option_control(&option_bytes)
+    }
+
+    /// Sets or clears the write protection of every sector overlapping `[start, end)`.
+    fn set_write_protection(
+        &mut self,
+        start: Address,
+        end: Address,
+        protected: bool,
+    ) -> nb::Result<(), Error> {
+        let option_bytes = self.option_bytes();
+        let write_protection = self
+            .sectors
+            .iter()
+            .filter(|sector| sector.start() < end && sector.end() > start)
+            .filter_map(Sector::number)
+            .fold(option_bytes.write_protection, |protection, number| {
+                if protected {
+                    protection.protect(number)
+                } else {
+                    protection.unprotect(number)
+                }
+            });
+        self.program_option_bytes(&OptionBytes { write_protection, ..option_bytes })
+    }
+
+    fn write_option_control(&mut self, option_bytes: &OptionBytes) -> nb::Result<(), Error> {
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.flash.optkeyr.write(|w| unsafe { w.bits(OPTION_UNLOCK_KEYS[0]) });
+        self.flash.optkeyr.write(|w| unsafe { w.bits(OPTION_UNLOCK_KEYS[1]) });
+        self.clear_errors();
+
+        let register = option_bytes.to_register(self.flash.optcr.read().bits());
+        let register = register & !(1 << option_control::OPTLOCK);
+        self.flash.optcr.write(|w| unsafe { w.bits(register) });
+        #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+        {
+            use option_control::{N_WRP, N_WRP_MASK};
+            let n_wrp = !(option_bytes.write_protection.0 >> SECOND_BANK_FIRST_SECTOR) & N_WRP_MASK;
+            self.flash.optcr1.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(N_WRP_MASK << N_WRP)) | (n_wrp << N_WRP))
+            });
+        }
+        self.flash.optcr.write(|w| unsafe { w.bits(register | (1 << option_control::OPTSTRT)) });
+        let result = self.wait_until_complete();
+        self.flash
+            .optcr
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << option_control::OPTLOCK)) });
+        Ok(result?)
+    }
+
     fn erase(&mut self, sector: &Sector) -> nb::Result<(), Error> {
-        let number = sector.number().ok_or(nb::Error::Other(Error::MemoryNotReachable))?;
+        let number = sector.selection().ok_or(nb::Error::Other(Error::MemoryNotReachable))?;
         self.unlock()?;
         self.flash
             .cr
             .modify(|_, w| unsafe { w.ser().set_bit().snb().bits(number).strt().set_bit() });
+        let result = self.wait_until_complete();
+        self.flash.cr.modify(|_, w| w.ser().clear_bit());
         self.lock();
-        Ok(())
+        Ok(result?)
     }
 
     fn is_busy(&self) -> bool { self.flash.sr.read().bsy().bit_is_set() }
 
+    fn read_raw(address: Address, bytes: &mut [u8]) {
+        let base = address.0 as *const u8;
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            // NOTE(Safety) we are reading directly from raw memory locations,
+            // which is inherently unsafe.
+            *byte = unsafe { *(base.add(index)) };
+        }
+    }
+
+    /// Waits for the ongoing operation to finish, and reports (and clears)
+    /// any error flags it raised.
+    fn wait_until_complete(&mut self) -> Result<(), Error> {
+        while self.is_busy() {}
+        let error = Error::from_status(self.flash.sr.read().bits());
+        self.clear_errors();
+        error.map_or(Ok(()), Err)
+    }
+
+    // Error flags are cleared by writing ones to them.
+    fn clear_errors(&mut self) { self.flash.sr.write(|w| unsafe { w.bits(status::ERROR_MASK) }); }
+
     fn write_bytes(
         &mut self,
         bytes: &[u8],
@@ -297,23 +945,37 @@ impl McuFlash {
                 *(base_address.add(index)) = word;
             }
         }
+        let result = self.wait_until_complete();
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
         self.lock();
-        Ok(())
+        Ok(result?)
     }
 }
 
-impl ReadWrite for McuFlash {
+fn otp_block_address(block: u8) -> Result<Address, Error> {
+    (block < OTP_BLOCK_COUNT)
+        .then_some(OTP_BASE + block as usize * OTP_BLOCK_SIZE)
+        .ok_or(Error::MemoryNotReachable)
+}
+
+fn otp_lock_address(block: u8) -> Result<Address, Error> {
+    (block < OTP_BLOCK_COUNT)
+        .then_some(OTP_LOCK_BASE + block as usize)
+        .ok_or(Error::MemoryNotReachable)
+}
+
+impl<const RESERVED_SECTORS: usize> ReadWrite for McuFlash<RESERVED_SECTORS> {
     type Error = Error;
     type Address = Address;
 
     fn range(&self) -> (Address, Address) {
-        (MemoryMap::writable_start(), MemoryMap::writable_end())
+        (writable_start(self.sectors), writable_end(self.sectors))
     }
 
     // NOTE: This only erases the sections of the MCU flash that are writable
     // from the application's perspective. Not the reserved sector, system bytes, etc.
     fn erase(&mut self) -> nb::Result<(), Self::Error> {
-        for sector in MEMORY_MAP.sectors.iter().filter(|s| s.is_writable()) {
+        for sector in self.sectors.iter().filter(|s| s.is_writable()) {
             self.erase(sector)?;
         }
         Ok(())
@@ -325,7 +987,7 @@ impl ReadWrite for McuFlash {
         }
 
         let range = Range(address, Address(address.0 + bytes.len() as u32));
-        if !range.is_writable() {
+        if !range.is_writable(self.sectors) {
             return Err(nb::Error::Other(Error::MemoryNotReachable));
         }
 
@@ -334,7 +996,8 @@ impl ReadWrite for McuFlash {
             return Err(nb::Error::WouldBlock);
         }
 
-        for (block, sector, address) in MemoryMap::sectors().overlaps(bytes, address) {
+        let sectors = self.sectors.iter().cloned();
+        for (block, sector, address) in sectors.overlaps(bytes, address) {
             let sector_data = &mut [0u8; max_sector_size()][0..sector.size];
             let offset_into_sector = address.0.saturating_sub(sector.start().0) as usize;
 
@@ -360,15 +1023,10 @@ impl ReadWrite for McuFlash {
 
     fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
         let range = Range(address, Address(address.0 + bytes.len() as u32));
-        if !range.is_writable() {
+        if !range.is_writable(self.sectors) {
             Err(nb::Error::Other(Error::MemoryNotReachable))
         } else {
-            let base = address.0 as *const u8;
-            for (index, byte) in bytes.iter_mut().enumerate() {
-                // NOTE(Safety) we are reading directly from raw memory locations,
-                // which is inherently unsafe.
-                *byte = unsafe { *(base.add(index)) };
-            }
+            Self::read_raw(address, bytes);
             Ok(())
         }
     }
@@ -379,33 +1037,30 @@ impl ReadWrite for McuFlash {
         blocks: I,
     ) -> Result<(), Self::Error> {
         const TRANSFER_SIZE: usize = KB!(4);
-        assert!(TRANSFER_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; TRANSFER_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % TRANSFER_SIZE)..((memory_index % TRANSFER_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % TRANSFER_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - TRANSFER_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
-        }
-        let remainder = &transfer_array[0..(memory_index % TRANSFER_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))?;
-        Ok(())
+        FlashWriter::<_, TRANSFER_SIZE>::new(self, address).write_blocks(blocks)
     }
 
     fn label() -> &'static str { "stm32f4 flash (Internal)" }
 }
 
+/// Write protection through the per-sector nWRP option bytes. Any main memory
+/// sector can be locked, including the reserved ones.
+impl<const RESERVED_SECTORS: usize> WriteProtect for McuFlash<RESERVED_SECTORS> {
+    fn lock_range(&mut self, start: Address, end: Address) -> nb::Result<(), Error> {
+        self.set_write_protection(start, end, true)
+    }
+
+    fn unlock_range(&mut self, start: Address, end: Address) -> nb::Result<(), Error> {
+        self.set_write_protection(start, end, false)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    const DEFAULT_MAP: MemoryMap = MemoryMap::reserving(DEFAULT_RESERVED_SECTORS);
+
     #[test]
     fn ranges_overlap_sectors_correctly() {
         let sector = Sector::new(Block::Reserved, Address(10), 10usize);
@@ -424,20 +1079,145 @@ mod test {
         let range = Range(Address(0x0801_1234), Address(0x0804_5678));
         let expected_sectors = &MEMORY_MAP.sectors[4..7];
 
-        assert_eq!(expected_sectors, range.span());
+        assert_eq!(expected_sectors, range.span(&DEFAULT_MAP.sectors));
     }
 
     #[test]
     fn map_shows_correct_writable_range() {
-        let (start, end) = (MemoryMap::writable_start(), MemoryMap::writable_end());
+        let (start, end) = (DEFAULT_MAP.writable_start(), DEFAULT_MAP.writable_end());
         assert_eq!(start, MEMORY_MAP.sectors[4].start());
-        assert_eq!(end, MEMORY_MAP.sectors[11].end());
+        let last_main_sector = MEMORY_MAP.sectors.iter().rev().find(|s| s.block == Block::Main);
+        assert_eq!(end, last_main_sector.unwrap().end());
+    }
+
+    #[test]
+    fn status_register_errors_are_decoded() {
+        assert_eq!(None, Error::from_status(0));
+        assert_eq!(None, Error::from_status(1 << 16)); // Busy, but no errors
+        assert_eq!(Some(Error::WriteProtected), Error::from_status(1 << status::WRPERR));
+        assert_eq!(Some(Error::ProgrammingSequence), Error::from_status(1 << status::PGSERR));
+        assert_eq!(Some(Error::OperationFailed), Error::from_status(1 << status::OPERR));
+        assert_eq!(
+            Some(Error::WriteProtected),
+            Error::from_status((1 << status::WRPERR) | (1 << status::PGSERR))
+        );
+    }
+
+    #[test]
+    fn option_bytes_round_trip_through_the_option_register() {
+        // Factory default: level 0, no protection, BOR off, software watchdog, no resets
+        let factory_default = 0x0FFF_AAED;
+        let option_bytes = OptionBytes::from_register(factory_default);
+        assert_eq!(option_bytes.read_protection, ReadProtection::Level0);
+        assert_eq!(option_bytes.write_protection, WriteProtection(0));
+        assert_eq!(option_bytes.brownout_level, BrownoutLevel::Off);
+        assert!(!option_bytes.hardware_watchdog);
+        assert!(!option_bytes.reset_on_stop);
+        assert!(!option_bytes.reset_on_standby);
+        assert_eq!(factory_default, option_bytes.to_register(factory_default));
+
+        let locked = OptionBytes {
+            read_protection: ReadProtection::Level1,
+            write_protection: WriteProtection::default().protect(0).protect(1),
+            brownout_level: BrownoutLevel::Level3,
+            ..option_bytes
+        };
+        let register = locked.to_register(factory_default);
+        assert_eq!(register & 0x3, factory_default & 0x3);
+        assert_eq!((register >> 16) & 0xFFF, 0xFFC);
+        assert_eq!(locked, OptionBytes::from_register(register));
+    }
+
+    #[test]
+    fn otp_blocks_and_lock_bytes_fit_the_otp_sector() {
+        let sector = Sector::one_time_programmable();
+        let last_block = otp_block_address(OTP_BLOCK_COUNT - 1).unwrap();
+        let last_lock = otp_lock_address(OTP_BLOCK_COUNT - 1).unwrap();
+        assert_eq!(otp_block_address(0).unwrap(), sector.start());
+        assert_eq!(last_block + OTP_BLOCK_SIZE, otp_lock_address(0).unwrap());
+        assert_eq!(last_lock + 1, sector.end());
+        assert_eq!(Err(Error::MemoryNotReachable), otp_block_address(OTP_BLOCK_COUNT));
+        assert_eq!(Err(Error::MemoryNotReachable), otp_lock_address(OTP_BLOCK_COUNT));
+    }
+
+    #[test]
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    fn second_bank_sectors_are_selected_from_the_second_bank_offset() {
+        let sectors = &MEMORY_MAP.sectors;
+        assert_eq!(Some(11), sectors[11].selection());
+        assert_eq!(Some(0b1_0000), sectors[12].selection());
+        assert_eq!(Some(0b1_1011), sectors[23].selection());
+        assert_eq!(DEFAULT_MAP.writable_end(), Address(0x0820_0000));
+    }
+
+    #[test]
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    fn main_sectors_are_split_evenly_between_banks() {
+        let in_bank = |bank| MEMORY_MAP.sectors.iter().filter(|s| s.bank() == Some(bank)).count();
+        assert_eq!(12, in_bank(Bank::First));
+        assert_eq!(12, in_bank(Bank::Second));
+        assert_eq!(None, Sector::one_time_programmable().bank());
+        assert_eq!(Some(Bank::Second), MEMORY_MAP.sectors[12].bank());
+    }
+
+    #[test]
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    fn single_bank_1mb_parts_keep_every_sector_in_the_first_bank() {
+        let map = SINGLE_BANK_1MB_MAP.reserve(DEFAULT_RESERVED_SECTORS);
+        assert!(map.is_sound());
+        assert_eq!(map.writable_end(), Address(0x0810_0000));
+        assert!(map.sectors.iter().filter_map(Sector::bank).all(|bank| bank == Bank::First));
+        assert_eq!(Some(11), map.sectors[11].selection());
+    }
+
+    #[test]
+    #[cfg(any(feature = "stm32f429", feature = "stm32f469"))]
+    fn dual_bank_1mb_parts_number_the_second_bank_from_sector_12() {
+        let map = DUAL_BANK_1MB_MAP.reserve(DEFAULT_RESERVED_SECTORS);
+        assert!(map.is_sound());
+        assert_eq!(map.writable_end(), Address(0x0810_0000));
+        assert_eq!(Some(7), map.sectors[7].selection());
+        assert_eq!(Some(Bank::Second), map.sectors[8].bank());
+        assert_eq!(Some(12), map.sectors[8].number());
+        assert_eq!(Some(0b1_0000), map.sectors[8].selection());
+        assert_eq!(Some(0b1_0111), map.sectors[15].selection());
+        assert_eq!(None, map.sectors[16].number());
+    }
+
+    #[test]
+    fn reserved_regions_can_be_resized() {
+        assert_eq!(2, sectors_to_reserve(KB!(32)));
+        assert_eq!(3, sectors_to_reserve(KB!(40)));
+        assert_eq!(DEFAULT_RESERVED_SECTORS, sectors_to_reserve(KB!(64)));
+        assert_eq!(5, sectors_to_reserve(KB!(128)));
+
+        const SMALL_MAP: MemoryMap = MemoryMap::reserving(sectors_to_reserve(KB!(32)));
+        assert!(SMALL_MAP.is_sound());
+        assert_eq!(SMALL_MAP.writable_start(), Address(0x0800_8000));
+        assert_eq!(SMALL_MAP.writable_end(), DEFAULT_MAP.writable_end());
+
+        let range = Range(Address(0x0800_8000), Address(0x0800_9000));
+        assert!(range.is_writable(&SMALL_MAP.sectors));
+        assert!(!range.is_writable(&DEFAULT_MAP.sectors));
+
+        const LARGE_MAP: MemoryMap = MemoryMap::reserving(sectors_to_reserve(KB!(128)));
+        assert_eq!(LARGE_MAP.writable_start(), Address(0x0802_0000));
+
+        const UNWRITABLE_MAP: MemoryMap = MemoryMap::reserving(SECTOR_NUMBER);
+        assert!(!UNWRITABLE_MAP.is_sound());
+    }
+
+    #[test]
+    fn default_constructor_needs_no_turbofish() {
+        // Only needs to compile: `new` must infer the default reservation
+        let _: fn(FLASH) -> Result<McuFlash, Error> = McuFlash::new;
+        let _: fn(FLASH) -> Result<McuFlash<2>, Error> = McuFlash::<2>::reserving;
     }
 
     #[test]
     fn ranges_are_correctly_marked_writable() {
         let (start, size) = (Address(0x0801_0008), 48usize);
         let range = Range(start, Address(start.0 + size as u32));
-        assert!(range.is_writable());
+        assert!(range.is_writable(&DEFAULT_MAP.sectors));
     }
 }
@@ -1,13 +1,22 @@
 //! USART implementation.
 use crate::{
-    drivers::stm32f4::{rcc, systick},
+    drivers::stm32f4::{
+        dma::{Direction, RxStream, TxStream},
+        rcc, systick,
+    },
     hal::{
         serial,
         time::{Milliseconds, Now},
     },
     stm32pac::{RCC, USART1, USART2, USART3, USART6},
+    utilities::buffer::RingBuffer,
+};
+use core::{
+    cmp::min,
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
 };
-use core::{marker::PhantomData, ptr};
 use defmt::Format;
 
 /// Extension trait to wrap a USART peripheral into a more useful
@@ -99,6 +108,8 @@ pub enum Error {
     Parity,
     /// Timeout error
     Timeout,
+    /// DMA transfer error
+    Dma,
 }
 
 /// Interrupt event
@@ -223,6 +234,39 @@ pub struct Serial<USART, PINS> {
     pins: PINS,
 }
 
+/// Serial driven from the USART interrupt, through fixed capacity ring buffers
+/// of `RX` and `TX` bytes.
+///
+/// [`on_interrupt`](Self::on_interrupt) must be called from the USART
+/// interrupt handler. As with `std`'s `&TcpStream`, the serial traits are
+/// implemented for shared references, so a single `'static` instance can be
+/// used by the application and its interrupt handler at the same time. Reads
+/// and writes should each happen from a single context.
+///
+/// # Example
+/// ```ignore
+/// let serial = serial.constrain((tx, rx), serial_config, clocks).unwrap();
+/// let serial: &'static _ = singleton!(: BufferedSerial<_, _, 256, 64> = serial.into_buffered()).unwrap();
+/// // In the USART2 interrupt handler: `serial.on_interrupt()`
+/// uwriteln!(&mut &*serial, "Hello!").unwrap();
+/// ```
+pub struct BufferedSerial<USART, PINS, const RX: usize, const TX: usize> {
+    serial: Serial<USART, PINS>,
+    rx: RingBuffer<RX>,
+    tx: RingBuffer<TX>,
+    overruns: AtomicU32,
+    errors: AtomicU32,
+}
+
+// NOTE(Safety) Shared references only reach the USART through single volatile
+// register accesses, plus the TXEIE read-modify-writes in `write_byte` and
+// `on_interrupt`, which run in critical sections so neither context can lose the
+// other's update. The ring buffers are single producer, single consumer.
+unsafe impl<USART: Send, PINS: Send, const RX: usize, const TX: usize> Sync
+    for BufferedSerial<USART, PINS, RX, TX>
+{
+}
+
 /// Serial receiver
 pub struct Rx<USART> {
     _usart: PhantomData<USART>,
@@ -233,6 +277,28 @@ pub struct Tx<USART> {
     _usart: PhantomData<USART>,
 }
 
+/// Serial receiver filling a circular buffer of `N` bytes through a DMA stream.
+///
+/// Reception runs continuously in the background, so the buffer must be drained
+/// faster than it fills; bytes that are overwritten before being read are lost.
+/// [`take_idle`](Self::take_idle) reports a pause on the line, which usually marks
+/// the end of a message. The receiver keeps the USART and its pins, which
+/// [`release`](Self::release) hands back together with both streams.
+pub struct DmaRx<USART, PINS, STREAM, const N: usize> {
+    serial: Serial<USART, PINS>,
+    stream: STREAM,
+    buffer: &'static mut [u8; N],
+    read: usize,
+}
+
+/// Serial transmitter sending byte slices through a DMA stream, staged in a
+/// buffer of `N` bytes.
+pub struct DmaTx<USART, STREAM, const N: usize> {
+    stream: STREAM,
+    buffer: &'static mut [u8; N],
+    _usart: PhantomData<USART>,
+}
+
 macro_rules! hal_usart_impl {
     ($(
         $USARTX:ident: ($usartX:ident, $apbXenr:ident, $usartXen:ident,  $pclkX:ident),
@@ -352,6 +418,287 @@ macro_rules! hal_usart_impl {
                 pub fn release(self) -> ($USARTX, PINS) {
                     (self.usart, self.pins)
                 }
+
+                /// Switches to interrupt driven operation, listening for received bytes.
+                pub fn into_buffered<const RX: usize, const TX: usize>(
+                    mut self,
+                ) -> BufferedSerial<$USARTX, PINS, RX, TX> {
+                    self.listen(Event::Rxne);
+                    BufferedSerial {
+                        serial: self,
+                        rx: RingBuffer::new(),
+                        tx: RingBuffer::new(),
+                        overruns: AtomicU32::new(0),
+                        errors: AtomicU32::new(0),
+                    }
+                }
+
+                /// Switches to DMA driven operation. Reception starts immediately into
+                /// `rx_buffer`, and `tx_buffer` stages outgoing slices.
+                pub fn into_dma<TXS, RXS, const TXN: usize, const RXN: usize>(
+                    self,
+                    tx_stream: TXS,
+                    tx_buffer: &'static mut [u8; TXN],
+                    mut rx_stream: RXS,
+                    rx_buffer: &'static mut [u8; RXN],
+                ) -> (DmaTx<$USARTX, TXS, TXN>, DmaRx<$USARTX, PINS, RXS, RXN>)
+                where
+                    TXS: TxStream<$USARTX>,
+                    RXS: RxStream<$USARTX>,
+                {
+                    assert!(TXN > 0 && TXN <= u16::MAX as usize);
+                    assert!(RXN > 0 && RXN <= u16::MAX as usize);
+                    self.usart.cr3.modify(|_, w| w.dmat().set_bit().dmar().set_bit());
+
+                    // NOTE(Safety) The buffer is 'static and owned by the receiver from now on
+                    unsafe {
+                        rx_stream.start(
+                            RXS::CHANNEL,
+                            &self.usart.dr as *const _ as u32,
+                            rx_buffer.as_mut_ptr() as u32,
+                            RXN as u16,
+                            Direction::PeripheralToMemory,
+                            true,
+                        );
+                    }
+
+                    (
+                        DmaTx { stream: tx_stream, buffer: tx_buffer, _usart: PhantomData },
+                        DmaRx { serial: self, stream: rx_stream, buffer: rx_buffer, read: 0 },
+                    )
+                }
+            }
+
+            impl<PINS, STREAM: RxStream<$USARTX>, const N: usize> DmaRx<$USARTX, PINS, STREAM, N> {
+                /// Bytes received and not yet read.
+                pub fn available(&self) -> usize {
+                    let written = (N - self.stream.remaining() as usize) % N;
+                    (written + N - self.read) % N
+                }
+
+                /// Copies as many received bytes as fit into `bytes`, returning how many were read.
+                pub fn read_available(&mut self, bytes: &mut [u8]) -> usize {
+                    let count = min(self.available(), bytes.len());
+                    for byte in bytes.iter_mut().take(count) {
+                        // NOTE(read_volatile) the buffer is written behind our back by the DMA stream
+                        *byte = unsafe { ptr::read_volatile(&self.buffer[self.read]) };
+                        self.read = (self.read + 1) % N;
+                    }
+                    count
+                }
+
+                /// Returns true if the line went idle since the last call, clearing the flag.
+                pub fn take_idle(&mut self) -> bool {
+                    let usart = &self.serial.usart;
+                    let idle = usart.sr.read().idle().bit_is_set();
+                    if idle {
+                        // The idle flag is cleared by reading the status register followed by the data register
+                        usart.dr.read();
+                    }
+                    idle
+                }
+
+                /// Stops both DMA streams and returns the USART to polled operation,
+                /// handing back the USART, its pins and the TX and RX streams.
+                pub fn release<TXS: TxStream<$USARTX>, const TXN: usize>(
+                    mut self,
+                    mut tx: DmaTx<$USARTX, TXS, TXN>,
+                ) -> ($USARTX, PINS, TXS, STREAM) {
+                    tx.stream.disable();
+                    self.stream.disable();
+                    self.serial.usart.cr3.modify(|_, w| w.dmat().clear_bit().dmar().clear_bit());
+                    let (usart, pins) = self.serial.release();
+                    (usart, pins, tx.stream, self.stream)
+                }
+            }
+
+            impl<PINS, STREAM: RxStream<$USARTX>, const N: usize> serial::Read for DmaRx<$USARTX, PINS, STREAM, N> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    let mut byte = [0u8];
+                    if self.read_available(&mut byte) == 1 {
+                        return Ok(byte[0]);
+                    }
+
+                    let usart = &self.serial.usart;
+                    if usart.sr.read().ore().bit_is_set() {
+                        usart.dr.read();
+                        Err(nb::Error::Other(Error::Overrun))
+                    } else if self.stream.has_failed() {
+                        self.stream.clear_flags();
+                        Err(nb::Error::Other(Error::Dma))
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            impl<STREAM: TxStream<$USARTX>, const N: usize> DmaTx<$USARTX, STREAM, N> {
+                /// Returns true while a previous slice is still being sent.
+                pub fn is_busy(&self) -> bool { self.stream.is_enabled() }
+
+                /// Starts sending as much of `bytes` as fits in the staging buffer, returning
+                /// how many bytes were queued. Doesn't wait for the transfer to complete.
+                pub fn write(&mut self, bytes: &[u8]) -> nb::Result<usize, Error> {
+                    if self.is_busy() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    if self.stream.has_failed() {
+                        self.stream.clear_flags();
+                        return Err(nb::Error::Other(Error::Dma));
+                    }
+
+                    let count = min(bytes.len(), N);
+                    if count == 0 {
+                        return Ok(0);
+                    }
+                    self.buffer[..count].copy_from_slice(&bytes[..count]);
+
+                    // NOTE(Safety) The staging buffer isn't touched again until the stream finishes
+                    unsafe {
+                        self.stream.start(
+                            STREAM::CHANNEL,
+                            &(*$USARTX::ptr()).dr as *const _ as u32,
+                            self.buffer.as_ptr() as u32,
+                            count as u16,
+                            Direction::MemoryToPeripheral,
+                            false,
+                        );
+                    }
+                    Ok(count)
+                }
+            }
+
+            impl<STREAM: TxStream<$USARTX>, const N: usize> serial::WriteBytes for DmaTx<$USARTX, STREAM, N> {
+                type Error = Error;
+
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    self.write(&[byte]).map(|_| ())
+                }
+
+                fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), Self::Error> {
+                    while !bytes.is_empty() {
+                        let sent = nb::block!(self.write(bytes))?;
+                        bytes = &bytes[sent..];
+                    }
+                    Ok(())
+                }
+
+                fn flush(&mut self) -> nb::Result<(), Error> {
+                    // NOTE(Safety) Atomic read on stateless register
+                    if self.is_busy() || unsafe { (*$USARTX::ptr()).sr.read().tc().bit_is_clear() } {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        Ok(())
+                    }
+                }
+            }
+
+            impl<STREAM: TxStream<$USARTX>, const N: usize> serial::Write for DmaTx<$USARTX, STREAM, N> {
+                type Error = Error;
+
+                fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+                    serial::WriteBytes::write_all(self, s.as_bytes())
+                }
+            }
+
+            impl<PINS, const RX: usize, const TX: usize> BufferedSerial<$USARTX, PINS, RX, TX> {
+                /// Moves received bytes into the RX buffer and queued bytes out of the
+                /// TX buffer. Must be called from the USART interrupt handler.
+                pub fn on_interrupt(&self) {
+                    let usart = &self.serial.usart;
+                    let sr = usart.sr.read();
+                    let corrupted = sr.pe().bit_is_set() || sr.fe().bit_is_set() || sr.nf().bit_is_set();
+
+                    if sr.rxne().bit_is_set() || sr.ore().bit_is_set() || corrupted {
+                        // Reading the data register also clears the error flags
+                        let byte = usart.dr.read().dr().bits() as u8;
+                        if sr.ore().bit_is_set() {
+                            self.overruns.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if corrupted {
+                            self.errors.fetch_add(1, Ordering::Relaxed);
+                        } else if sr.rxne().bit_is_set() && self.rx.push(byte).is_err() {
+                            self.overruns.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+
+                    if sr.txe().bit_is_set() && usart.cr1.read().txeie().bit_is_set() {
+                        // Checked and cleared atomically, so a byte queued by a higher
+                        // priority writer can't be stranded with TXEIE off.
+                        cortex_m::interrupt::free(|_| match self.tx.pop() {
+                            Some(byte) => usart.dr.write(|w| w.dr().bits(byte.into())),
+                            None => usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+                        });
+                    }
+                }
+
+                /// Bytes lost because the hardware or the RX buffer overflowed.
+                pub fn overrun_count(&self) -> u32 { self.overruns.load(Ordering::Relaxed) }
+
+                /// Bytes discarded due to framing, noise or parity errors.
+                pub fn error_count(&self) -> u32 { self.errors.load(Ordering::Relaxed) }
+
+                /// Stops interrupt driven operation, discarding any buffered bytes.
+                pub fn release(mut self) -> Serial<$USARTX, PINS> {
+                    self.serial.unlisten(Event::Rxne);
+                    self.serial.unlisten(Event::Txe);
+                    self.serial
+                }
+
+            }
+
+            impl<'a, PINS, const RX: usize, const TX: usize> serial::Read for &'a BufferedSerial<$USARTX, PINS, RX, TX> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    self.rx.pop().ok_or(nb::Error::WouldBlock)
+                }
+            }
+
+            impl<'a, PINS, const RX: usize, const TX: usize> serial::TimeoutRead for &'a BufferedSerial<$USARTX, PINS, RX, TX> {
+                type Error = Error;
+
+                fn read<T: Copy + Into<Milliseconds>>(&mut self, timeout: T) -> Result<u8, Self::Error> {
+                    let start = systick::SysTick::now();
+                    while (systick::SysTick::now() - start) < timeout.into() {
+                        if let Some(byte) = self.rx.pop() {
+                            return Ok(byte);
+                        }
+                    }
+                    Err(Error::Timeout)
+                }
+            }
+
+            impl<'a, PINS, const RX: usize, const TX: usize> serial::WriteBytes for &'a BufferedSerial<$USARTX, PINS, RX, TX> {
+                type Error = Error;
+
+                /// Queues a byte for transmission, or returns `WouldBlock` if the TX buffer is full.
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    self.tx.push(byte).map_err(|_| nb::Error::WouldBlock)?;
+                    // The interrupt handler clears TXEIE, so it must not preempt this update
+                    cortex_m::interrupt::free(|_| {
+                        self.serial.usart.cr1.modify(|_, w| w.txeie().set_bit())
+                    });
+                    Ok(())
+                }
+
+                fn flush(&mut self) -> nb::Result<(), Error> {
+                    if self.tx.is_empty() && self.serial.usart.sr.read().tc().bit_is_set() {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            impl<'a, PINS, const RX: usize, const TX: usize> serial::Write for &'a BufferedSerial<$USARTX, PINS, RX, TX> {
+                type Error = Error;
+
+                fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+                    serial::WriteBytes::write_all(self, s.as_bytes())
+                }
             }
 
             impl<PINS> serial::Read for Serial<$USARTX, PINS> {
@@ -446,42 +793,62 @@ macro_rules! hal_usart_impl {
                 }
             }
 
-            impl<PINS> serial::Write for Serial<$USARTX, PINS> {
+            impl<PINS> serial::WriteBytes for Serial<$USARTX, PINS> {
                 type Error = Error;
 
-                fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
                     let mut tx: Tx<$USARTX> = Tx {
                         _usart: PhantomData,
                     };
-                    tx.write_str(s)
+                    tx.write_byte(byte)
                 }
 
-                fn write_char(&mut self, c: char) -> Result<(), Self::Error> {
+                fn flush(&mut self) -> nb::Result<(), Self::Error> {
                     let mut tx: Tx<$USARTX> = Tx {
                         _usart: PhantomData,
                     };
-                    tx.write_char(c)
+                    tx.flush()
                 }
             }
 
-            impl serial::Write for Tx<$USARTX> {
+            impl<PINS> serial::Write for Serial<$USARTX, PINS> {
                 type Error = Error;
 
                 fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
-                    for character in s.chars() {
-                        self.write_char(character)?;
-                    }
-                    Ok(())
+                    serial::WriteBytes::write_all(self, s.as_bytes())
                 }
+            }
 
-                fn write_char(&mut self, c: char) -> Result<(), Self::Error> {
+            impl serial::WriteBytes for Tx<$USARTX> {
+                type Error = Error;
+
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
                     // NOTE(Safety) atomic read with no side effects
-                    while ! unsafe { (*$USARTX::ptr()).sr.read().txe().bit_is_set() } {}
+                    if unsafe { (*$USARTX::ptr()).sr.read().txe().bit_is_clear() } {
+                        return Err(nb::Error::WouldBlock);
+                    }
                     // NOTE(Safety) atomic write to stateless register
                     // NOTE(write_volatile) 8-bit write that's not possible through the svd2rust API
-                    unsafe { ptr::write_volatile(&(*$USARTX::ptr()).dr as *const _ as *mut _, c as u8) }
+                    unsafe { ptr::write_volatile(&(*$USARTX::ptr()).dr as *const _ as *mut _, byte) }
                     Ok(())
                 }
+
+                fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                    // NOTE(Safety) atomic read with no side effects
+                    if unsafe { (*$USARTX::ptr()).sr.read().tc().bit_is_set() } {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            impl serial::Write for Tx<$USARTX> {
+                type Error = Error;
+
+                fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+                    serial::WriteBytes::write_all(self, s.as_bytes())
+                }
             }
         )+
     }
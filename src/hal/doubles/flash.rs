@@ -51,10 +51,10 @@ impl flash::ReadWrite for FakeFlash {
 
     fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
         &mut self,
-        _address: Self::Address,
-        _blocks: I,
+        address: Self::Address,
+        blocks: I,
     ) -> Result<(), Self::Error> {
-        todo!()
+        flash::FlashWriter::<_, 64>::new(self, address).write_blocks(blocks)
     }
 
     fn label() -> &'static str { "Fake Flash" }
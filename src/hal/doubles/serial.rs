@@ -1,12 +1,25 @@
 use crate::hal::{serial, time};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SerialStubError;
 pub struct SerialStub;
 
+impl serial::WriteBytes for SerialStub {
+    type Error = SerialStubError;
+    fn write_byte(&mut self, _byte: u8) -> nb::Result<(), Self::Error> { Ok(()) }
+    fn flush(&mut self) -> nb::Result<(), Self::Error> { Ok(()) }
+}
+
 impl serial::Write for SerialStub {
     type Error = SerialStubError;
-    fn write_str(&mut self, _s: &str) -> Result<(), Self::Error> { Ok(()) }
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        serial::WriteBytes::write_all(self, s.as_bytes())
+    }
 }
 
 impl serial::Read for SerialStub {
@@ -21,3 +34,205 @@ impl serial::TimeoutRead for SerialStub {
         Ok(0)
     }
 }
+
+/// Serial double that replays scripted bytes and records everything written.
+/// Reads time out once the script runs out.
+#[derive(Default)]
+pub struct MockSerial {
+    pub to_read: VecDeque<u8>,
+    pub written: Vec<u8>,
+}
+
+impl serial::WriteBytes for MockSerial {
+    type Error = SerialStubError;
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.written.push(byte);
+        Ok(())
+    }
+    fn flush(&mut self) -> nb::Result<(), Self::Error> { Ok(()) }
+}
+
+impl serial::TimeoutRead for MockSerial {
+    type Error = SerialStubError;
+
+    fn read<T: Copy + Into<time::Milliseconds>>(&mut self, _timeout: T) -> Result<u8, Self::Error> {
+        self.to_read.pop_front().ok_or(SerialStubError)
+    }
+}
+
+/// Errors an impaired link can report in place of a byte.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LinkError {
+    Overrun,
+    Framing,
+    Parity,
+    Timeout,
+}
+
+/// Faults injected into bytes travelling in one direction over a [`connected_pair`].
+/// Byte indices count every byte written in that direction, starting at zero.
+#[derive(Clone, Debug, Default)]
+pub struct Impairments {
+    drops: Vec<usize>,
+    drop_every: Option<usize>,
+    flips: Vec<(usize, u8)>,
+    errors: Vec<(usize, LinkError)>,
+    latency: Duration,
+}
+
+impl Impairments {
+    pub fn new() -> Self { Self::default() }
+
+    /// Loses the byte at `index`.
+    pub fn drop_byte(mut self, index: usize) -> Self {
+        self.drops.push(index);
+        self
+    }
+
+    /// Loses every `period`th byte. A period of zero loses nothing.
+    pub fn drop_every(mut self, period: usize) -> Self {
+        self.drop_every = Some(period).filter(|period| *period > 0);
+        self
+    }
+
+    /// Inverts the bits set in `mask` on the byte at `index`.
+    pub fn flip(mut self, index: usize, mask: u8) -> Self {
+        self.flips.push((index, mask));
+        self
+    }
+
+    /// Reports `error` to the reader instead of the byte at `index`.
+    pub fn error(mut self, index: usize, error: LinkError) -> Self {
+        self.errors.push((index, error));
+        self
+    }
+
+    /// Delays every byte by `latency` before it can be read.
+    pub fn latency<T: Into<time::Milliseconds>>(mut self, latency: T) -> Self {
+        self.latency = Duration::from_millis(latency.into().0 as u64);
+        self
+    }
+
+    fn apply(&self, index: usize, byte: u8) -> Option<Result<u8, LinkError>> {
+        let dropped = self.drops.contains(&index)
+            || self.drop_every.map_or(false, |period| (index + 1) % period == 0);
+        if dropped {
+            return None;
+        }
+        if let Some((_, error)) = self.errors.iter().find(|(i, _)| *i == index) {
+            return Some(Err(*error));
+        }
+        let mask = self.flips.iter().filter(|(i, _)| *i == index).fold(0, |mask, (_, m)| mask | m);
+        Some(Ok(byte ^ mask))
+    }
+}
+
+#[derive(Default)]
+struct Direction {
+    in_flight: VecDeque<(Instant, Result<u8, LinkError>)>,
+    impairments: Impairments,
+    written: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Wire {
+    direction: Mutex<Direction>,
+    arrival: Condvar,
+}
+
+/// One end of a [`connected_pair`]. Bytes written here can be read from the other
+/// end, after going through the impairments configured for this direction.
+pub struct SerialEndpoint {
+    incoming: Arc<Wire>,
+    outgoing: Arc<Wire>,
+}
+
+/// Builds two connected serial endpoints. Each can be moved to its own
+/// thread, so that both sides of a protocol run against each other.
+pub fn connected_pair() -> (SerialEndpoint, SerialEndpoint) {
+    let (a_to_b, b_to_a) = (Arc::new(Wire::default()), Arc::new(Wire::default()));
+    (
+        SerialEndpoint { incoming: b_to_a.clone(), outgoing: a_to_b.clone() },
+        SerialEndpoint { incoming: a_to_b, outgoing: b_to_a },
+    )
+}
+
+impl SerialEndpoint {
+    /// Applies `impairments` to every byte written from this endpoint from now on.
+    pub fn impair_outgoing(&mut self, impairments: Impairments) {
+        self.outgoing.direction.lock().unwrap().impairments = impairments;
+    }
+
+    /// Makes `bytes` available to read from this endpoint, unimpaired.
+    pub fn script(&mut self, bytes: &[u8]) {
+        let now = Instant::now();
+        let mut incoming = self.incoming.direction.lock().unwrap();
+        incoming.in_flight.extend(bytes.iter().map(|byte| (now, Ok(*byte))));
+        self.incoming.arrival.notify_all();
+    }
+
+    /// Everything written from this endpoint so far, before impairments.
+    pub fn written(&self) -> Vec<u8> { self.outgoing.direction.lock().unwrap().written.clone() }
+
+    fn receive(&mut self, deadline: Option<Instant>) -> Result<u8, Option<LinkError>> {
+        let mut incoming = self.incoming.direction.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let wait = match incoming.in_flight.front() {
+                Some((arrival, _)) if *arrival <= now => {
+                    return incoming.in_flight.pop_front().unwrap().1.map_err(Some)
+                }
+                Some((arrival, _)) => *arrival - now,
+                None => Duration::MAX,
+            };
+            let wait = match deadline {
+                Some(deadline) if deadline <= now => return Err(Some(LinkError::Timeout)),
+                Some(deadline) => wait.min(deadline - now),
+                None => return Err(None),
+            };
+            incoming = self.incoming.arrival.wait_timeout(incoming, wait).unwrap().0;
+        }
+    }
+}
+
+impl serial::WriteBytes for SerialEndpoint {
+    type Error = LinkError;
+
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        let mut outgoing = self.outgoing.direction.lock().unwrap();
+        let index = outgoing.written.len();
+        outgoing.written.push(byte);
+        if let Some(received) = outgoing.impairments.apply(index, byte) {
+            let arrival = Instant::now() + outgoing.impairments.latency;
+            outgoing.in_flight.push_back((arrival, received));
+            self.outgoing.arrival.notify_all();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> { Ok(()) }
+}
+
+impl serial::Write for SerialEndpoint {
+    type Error = LinkError;
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        serial::WriteBytes::write_all(self, s.as_bytes())
+    }
+}
+
+impl serial::Read for SerialEndpoint {
+    type Error = LinkError;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.receive(None).map_err(|error| error.map_or(nb::Error::WouldBlock, nb::Error::Other))
+    }
+}
+
+impl serial::TimeoutRead for SerialEndpoint {
+    type Error = LinkError;
+
+    fn read<T: Copy + Into<time::Milliseconds>>(&mut self, timeout: T) -> Result<u8, Self::Error> {
+        let deadline = Instant::now() + Duration::from_millis(timeout.into().0 as u64);
+        self.receive(Some(deadline)).map_err(|error| error.unwrap_or(LinkError::Timeout))
+    }
+}
@@ -0,0 +1,144 @@
+use super::{FlashWriter, Offset, ReadWrite};
+use core::cmp::min;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConcatError<A, B> {
+    First(A),
+    Second(B),
+    OutOfBounds,
+}
+
+/// Presents two flash devices as a single address space, with `B`'s range
+/// following straight after `A`'s. Operations that straddle the boundary are
+/// split between both devices.
+///
+/// More devices can be joined by nesting, e.g. `Concat<A, Concat<B, C>>`.
+pub struct Concat<A: ReadWrite, B: ReadWrite> {
+    first: A,
+    second: B,
+}
+
+impl<A: ReadWrite, B: ReadWrite> Concat<A, B> {
+    pub fn new(first: A, second: B) -> Self { Self { first, second } }
+    pub fn into_inner(self) -> (A, B) { (self.first, self.second) }
+
+    fn first_length(&self) -> usize {
+        let (start, end) = self.first.range();
+        end - start
+    }
+
+    fn second_length(&self) -> usize {
+        let (start, end) = self.second.range();
+        end - start
+    }
+
+    /// Length of the leading part of an operation that lands on the first device.
+    fn first_part(
+        &self,
+        Offset(offset): Offset,
+        length: usize,
+    ) -> Result<usize, ConcatError<A::Error, B::Error>> {
+        if offset + length > self.first_length() + self.second_length() {
+            Err(ConcatError::OutOfBounds)
+        } else {
+            Ok(min(self.first_length().saturating_sub(offset), length))
+        }
+    }
+
+    fn first_address(&self, Offset(offset): Offset) -> A::Address { self.first.range().0 + offset }
+
+    fn second_address(&self, Offset(offset): Offset) -> B::Address {
+        self.second.range().0 + (offset - self.first_length())
+    }
+}
+
+impl<A: ReadWrite, B: ReadWrite> ReadWrite for Concat<A, B> {
+    type Error = ConcatError<A::Error, B::Error>;
+    type Address = Offset;
+
+    fn label() -> &'static str { "Concatenated flash" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let first_part = self.first_part(address, bytes.len())?;
+        let (first, second) = bytes.split_at_mut(first_part);
+        if !first.is_empty() {
+            let first_address = self.first_address(address);
+            nb::block!(self.first.read(first_address, first)).map_err(ConcatError::First)?;
+        }
+        if !second.is_empty() {
+            let second_address = self.second_address(address + first_part);
+            nb::block!(self.second.read(second_address, second)).map_err(ConcatError::Second)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        let first_part = self.first_part(address, bytes.len())?;
+        let (first, second) = bytes.split_at(first_part);
+        if !first.is_empty() {
+            let first_address = self.first_address(address);
+            nb::block!(self.first.write(first_address, first)).map_err(ConcatError::First)?;
+        }
+        if !second.is_empty() {
+            let second_address = self.second_address(address + first_part);
+            nb::block!(self.second.write(second_address, second)).map_err(ConcatError::Second)?;
+        }
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) {
+        (Offset(0), Offset(self.first_length() + self.second_length()))
+    }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        nb::block!(self.first.erase()).map_err(ConcatError::First)?;
+        nb::block!(self.second.erase()).map_err(ConcatError::Second)?;
+        Ok(())
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        FlashWriter::<_, { KB!(4) }>::new(self, address).write_blocks(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::flash::{Address, FakeFlash};
+
+    #[test]
+    fn concatenated_flash_splits_straddling_operations() {
+        // Given
+        let boundary = MB!(16);
+        let mut flash = Concat::new(FakeFlash::new(Address(0)), FakeFlash::new(Address(0x100)));
+        assert_eq!(flash.range(), (Offset(0), Offset(2 * boundary)));
+
+        // When
+        flash.write(Offset(boundary - 2), &[1, 2, 3, 4]).unwrap();
+
+        // Then
+        let mut bytes = [0u8; 4];
+        flash.read(Offset(boundary - 2), &mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4]);
+        let (mut first, mut second) = flash.into_inner();
+        first.read(Address(boundary as u32 - 2), &mut bytes[..2]).unwrap();
+        second.read(Address(0x100), &mut bytes[2..]).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn concatenated_flash_rejects_operations_past_the_end() {
+        // Given
+        let mut flash = Concat::new(FakeFlash::new(Address(0)), FakeFlash::new(Address(0)));
+
+        // When
+        let result = flash.write(Offset(MB!(32) - 1), &[0u8; 2]);
+
+        // Then
+        assert_eq!(result, Err(nb::Error::Other(ConcatError::OutOfBounds)));
+    }
+}
@@ -0,0 +1,174 @@
+use super::ReadWrite;
+use core::cmp::min;
+use nom::IResult;
+
+/// Position to seek a `FlashCursor` to, following `std::io::SeekFrom`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    End(usize),
+    Current(isize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CursorError<E> {
+    /// The underlying flash failed to read.
+    Flash(E),
+    /// The operation would leave the cursor's region.
+    OutOfBounds,
+    /// A parser needed more bytes than the window or region could provide.
+    Incomplete,
+    /// A parser rejected the bytes under the cursor.
+    Parse(nom::error::ErrorKind),
+}
+
+/// Reader over a bounded region of flash, tracking a position within it.
+pub struct FlashCursor<'a, F: ReadWrite + ?Sized> {
+    flash: &'a mut F,
+    start: F::Address,
+    length: usize,
+    position: usize,
+}
+
+impl<'a, F: ReadWrite + ?Sized> FlashCursor<'a, F> {
+    /// Bounds the cursor to `[start, end)`, positioned at `start`.
+    pub fn new(flash: &'a mut F, start: F::Address, end: F::Address) -> Self {
+        Self { flash, start, length: end - start, position: 0 }
+    }
+
+    /// Offset from the start of the region.
+    pub fn position(&self) -> usize { self.position }
+    pub fn len(&self) -> usize { self.length }
+    pub fn is_empty(&self) -> bool { self.length == 0 }
+    pub fn remaining(&self) -> usize { self.length - self.position }
+
+    /// Reads as many bytes as fit in `bytes` or remain in the region,
+    /// returning how many were read.
+    pub fn read(&mut self, bytes: &mut [u8]) -> Result<usize, CursorError<F::Error>> {
+        let count = self.peek(bytes)?;
+        self.position += count;
+        Ok(count)
+    }
+
+    /// Fills `bytes` completely, failing without moving if the region ends first.
+    pub fn read_exact(&mut self, bytes: &mut [u8]) -> Result<(), CursorError<F::Error>> {
+        if bytes.len() > self.remaining() {
+            return Err(CursorError::OutOfBounds);
+        }
+        self.read(bytes).map(|_| ())
+    }
+
+    /// Like `read`, but leaves the position untouched.
+    pub fn peek(&mut self, bytes: &mut [u8]) -> Result<usize, CursorError<F::Error>> {
+        let count = min(bytes.len(), self.remaining());
+        let address = self.start + self.position;
+        nb::block!(self.flash.read(address, &mut bytes[..count])).map_err(CursorError::Flash)?;
+        Ok(count)
+    }
+
+    /// Moves the cursor, returning the new position. Seeking to the very end is allowed.
+    pub fn seek(&mut self, target: SeekFrom) -> Result<usize, CursorError<F::Error>> {
+        let position = match target {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_sub(offset),
+            SeekFrom::Current(offset) if offset < 0 => {
+                self.position.checked_sub(offset.unsigned_abs())
+            }
+            SeekFrom::Current(offset) => self.position.checked_add(offset as usize),
+        };
+        match position {
+            Some(position) if position <= self.length => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(CursorError::OutOfBounds),
+        }
+    }
+
+    /// Runs a nom parser over the bytes under the cursor, advancing past what it consumes.
+    ///
+    /// Up to `window.len()` bytes are read into `window` first, so the output may
+    /// borrow from it. Streaming parsers report `CursorError::Incomplete` when the
+    /// structure is larger than the window or runs past the end of the region.
+    pub fn parse<'b, O, P>(
+        &mut self,
+        window: &'b mut [u8],
+        mut parser: P,
+    ) -> Result<O, CursorError<F::Error>>
+    where
+        P: FnMut(&'b [u8]) -> IResult<&'b [u8], O>,
+    {
+        let count = self.peek(window)?;
+        let input: &'b [u8] = &window[..count];
+        match parser(input) {
+            Ok((rest, output)) => {
+                self.position += count - rest.len();
+                Ok(output)
+            }
+            Err(nom::Err::Incomplete(_)) => Err(CursorError::Incomplete),
+            Err(nom::Err::Error(error)) | Err(nom::Err::Failure(error)) => {
+                Err(CursorError::Parse(error.code))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::flash::{Address, FakeFlash};
+    use nom::error::ErrorKind;
+
+    #[test]
+    fn cursor_reads_peeks_and_seeks_within_its_region() {
+        // Given
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), &(0..32).collect::<Vec<u8>>()).unwrap();
+        let mut cursor = FlashCursor::new(&mut flash, Address(8), Address(16));
+        let mut bytes = [0u8; 4];
+
+        // When
+        cursor.peek(&mut bytes).unwrap();
+
+        // Then
+        assert_eq!(bytes, [8, 9, 10, 11]);
+        assert_eq!(cursor.position(), 0);
+
+        // When
+        cursor.seek(SeekFrom::End(2)).unwrap();
+        let count = cursor.read(&mut bytes).unwrap();
+
+        // Then
+        assert_eq!(&bytes[..count], &[14, 15]);
+        assert_eq!(cursor.remaining(), 0);
+        assert_eq!(cursor.seek(SeekFrom::Current(1)), Err(CursorError::OutOfBounds));
+        assert_eq!(cursor.read_exact(&mut bytes), Err(CursorError::OutOfBounds));
+        assert_eq!(cursor.seek(SeekFrom::Current(-8)), Ok(0));
+    }
+
+    #[test]
+    fn cursor_parses_structures_in_place() {
+        use nom::{bytes::streaming::tag, number::streaming::le_u16};
+        fn header(input: &[u8]) -> IResult<&[u8], u16> {
+            let (input, _) = tag(b"HD")(input)?;
+            le_u16(input)
+        }
+
+        // Given
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), &[b'H', b'D', 0x34, 0x12, 0xFF]).unwrap();
+        let mut cursor = FlashCursor::new(&mut flash, Address(0), Address(5));
+        let mut window = [0u8; 16];
+
+        // When
+        let value = cursor.parse(&mut window, header).unwrap();
+
+        // Then
+        assert_eq!(value, 0x1234);
+        assert_eq!(cursor.position(), 4);
+        assert_eq!(cursor.parse(&mut window, header), Err(CursorError::Parse(ErrorKind::Tag)));
+        cursor.seek(SeekFrom::Start(2)).unwrap();
+        assert_eq!(cursor.parse(&mut window, le_u16), Ok(0x1234));
+        assert_eq!(cursor.parse(&mut window, le_u16), Err(CursorError::Incomplete));
+    }
+}
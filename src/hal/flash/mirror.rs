@@ -0,0 +1,289 @@
+use super::{FlashWriter, Offset, ReadWrite};
+use core::{cmp::min, mem::size_of};
+use crc::crc32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MirrorError<A, B> {
+    Primary(A),
+    Secondary(B),
+    /// Neither copy of a record passed its CRC check.
+    Corrupted,
+    OutOfBounds,
+}
+
+const RECORD_CRC_SIZE: usize = size_of::<u32>();
+
+/// Whether a record copy reads as freshly erased flash.
+fn is_blank(record: &[u8]) -> bool { record.iter().all(|&byte| byte == 0xFF) }
+
+/// Keeps two copies of the same data on separate devices or partitions.
+///
+/// Data is stored in records of `RECORD` bytes: a payload of `RECORD - 4` bytes
+/// followed by its CRC-32, written together in a single call. Reads serve
+/// whichever copy passes its check and rewrite the other one if it doesn't.
+/// When both copies are valid but differ (e.g. power was lost between the two
+/// writes) the primary, which is always written first, wins. Records never
+/// written since the last erase (both copies all `0xFF`) read as blank.
+pub struct Mirror<A: ReadWrite, B: ReadWrite, const RECORD: usize> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A: ReadWrite, B: ReadWrite, const RECORD: usize> Mirror<A, B, RECORD> {
+    const PAYLOAD: usize = {
+        assert!(RECORD > RECORD_CRC_SIZE, "Records must fit a payload and its CRC");
+        RECORD - RECORD_CRC_SIZE
+    };
+
+    pub fn new(primary: A, secondary: B) -> Self { Self { primary, secondary } }
+    pub fn into_inner(self) -> (A, B) { (self.primary, self.secondary) }
+
+    fn records(&self) -> usize {
+        let (primary_start, primary_end) = self.primary.range();
+        let (secondary_start, secondary_end) = self.secondary.range();
+        min(primary_end - primary_start, secondary_end - secondary_start) / RECORD
+    }
+
+    fn is_valid(record: &[u8; RECORD]) -> bool {
+        let (payload, crc) = record.split_at(Self::PAYLOAD);
+        crc32::checksum_ieee(payload).to_le_bytes() == crc
+    }
+
+    /// Computes the CRC of the record's payload into its tail.
+    fn seal(record: &mut [u8; RECORD]) {
+        let crc = crc32::checksum_ieee(&record[..Self::PAYLOAD]).to_le_bytes();
+        record[Self::PAYLOAD..].copy_from_slice(&crc);
+    }
+
+    /// Reads the good copy of a record, repairing the other copy if needed.
+    fn read_record(
+        &mut self,
+        index: usize,
+        record: &mut [u8; RECORD],
+    ) -> Result<(), MirrorError<A::Error, B::Error>> {
+        let mut secondary = [0u8; RECORD];
+        let address = self.primary.range().0 + index * RECORD;
+        nb::block!(self.primary.read(address, record)).map_err(MirrorError::Primary)?;
+        let address = self.secondary.range().0 + index * RECORD;
+        nb::block!(self.secondary.read(address, &mut secondary)).map_err(MirrorError::Secondary)?;
+
+        match (Self::is_valid(record), Self::is_valid(&secondary)) {
+            (true, true) if *record == secondary => Ok(()),
+            (true, _) => self.write_secondary(index, record),
+            (false, true) => {
+                *record = secondary;
+                self.write_primary(index, record)
+            }
+            (false, false) if is_blank(record) && is_blank(&secondary) => Ok(()),
+            (false, false) => Err(MirrorError::Corrupted),
+        }
+    }
+
+    fn write_primary(
+        &mut self,
+        index: usize,
+        record: &[u8; RECORD],
+    ) -> Result<(), MirrorError<A::Error, B::Error>> {
+        let address = self.primary.range().0 + index * RECORD;
+        nb::block!(self.primary.write(address, record)).map_err(MirrorError::Primary)
+    }
+
+    fn write_secondary(
+        &mut self,
+        index: usize,
+        record: &[u8; RECORD],
+    ) -> Result<(), MirrorError<A::Error, B::Error>> {
+        let address = self.secondary.range().0 + index * RECORD;
+        nb::block!(self.secondary.write(address, record)).map_err(MirrorError::Secondary)
+    }
+
+    fn check_bounds(
+        &self,
+        Offset(offset): Offset,
+        length: usize,
+    ) -> Result<(), MirrorError<A::Error, B::Error>> {
+        if offset + length > self.records() * Self::PAYLOAD {
+            Err(MirrorError::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<A: ReadWrite, B: ReadWrite, const RECORD: usize> ReadWrite for Mirror<A, B, RECORD> {
+    type Error = MirrorError<A::Error, B::Error>;
+    type Address = Offset;
+
+    fn label() -> &'static str { "Mirrored flash" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        self.check_bounds(address, bytes.len())?;
+        let mut record = [0u8; RECORD];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = address.0 + done;
+            let (index, within) = (position / Self::PAYLOAD, position % Self::PAYLOAD);
+            let count = min(Self::PAYLOAD - within, bytes.len() - done);
+            self.read_record(index, &mut record)?;
+            bytes[done..done + count].copy_from_slice(&record[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        self.check_bounds(address, bytes.len())?;
+        let mut record = [0u8; RECORD];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = address.0 + done;
+            let (index, within) = (position / Self::PAYLOAD, position % Self::PAYLOAD);
+            let count = min(Self::PAYLOAD - within, bytes.len() - done);
+            if count < Self::PAYLOAD {
+                // Partially written records keep their contents, or start blank if lost.
+                match self.read_record(index, &mut record) {
+                    Err(MirrorError::Corrupted) => record = [0xFF; RECORD],
+                    result => result?,
+                }
+            }
+            record[within..within + count].copy_from_slice(&bytes[done..done + count]);
+            Self::seal(&mut record);
+            self.write_primary(index, &record)?;
+            self.write_secondary(index, &record)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) {
+        (Offset(0), Offset(self.records() * Self::PAYLOAD))
+    }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        nb::block!(self.primary.erase()).map_err(MirrorError::Primary)?;
+        nb::block!(self.secondary.erase()).map_err(MirrorError::Secondary)?;
+        Ok(())
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        FlashWriter::<_, RECORD>::new(self, address).write_blocks(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::{
+        error::FakeError,
+        flash::{Address, FakeFlash},
+    };
+
+    /// Fake flash that records the span of every write it receives.
+    struct LoggingFlash(FakeFlash, Vec<(Address, usize)>);
+
+    impl ReadWrite for LoggingFlash {
+        type Error = FakeError;
+        type Address = Address;
+
+        fn label() -> &'static str { "Logging Flash" }
+
+        fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), FakeError> {
+            self.0.read(address, bytes)
+        }
+
+        fn write(&mut self, address: Address, bytes: &[u8]) -> nb::Result<(), FakeError> {
+            self.1.push((address, bytes.len()));
+            self.0.write(address, bytes)
+        }
+
+        fn range(&self) -> (Address, Address) { self.0.range() }
+
+        fn erase(&mut self) -> nb::Result<(), FakeError> { self.0.erase() }
+
+        fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+            &mut self,
+            address: Address,
+            blocks: I,
+        ) -> Result<(), FakeError> {
+            FlashWriter::<_, 20>::new(self, address).write_blocks(blocks)
+        }
+    }
+
+    #[test]
+    fn mirrored_flash_serves_and_repairs_the_good_copy() {
+        // Given
+        let mut flash =
+            Mirror::<_, _, 20>::new(FakeFlash::new(Address(0)), FakeFlash::new(Address(0)));
+        let data: Vec<u8> = (0..40).collect();
+        flash.write(Offset(3), &data).unwrap();
+        let (mut primary, secondary) = flash.into_inner();
+        primary.write(Address(25), &[0xAA]).unwrap(); // Inside the second record
+        let mut flash = Mirror::<_, _, 20>::new(primary, secondary);
+
+        // When
+        let mut bytes = [0u8; 40];
+        flash.read(Offset(3), &mut bytes).unwrap();
+
+        // Then
+        assert_eq!(&bytes[..], &data[..]);
+        let (mut primary, mut secondary) = flash.into_inner();
+        let (mut repaired, mut original) = ([0u8; 20], [0u8; 20]);
+        primary.read(Address(20), &mut repaired).unwrap();
+        secondary.read(Address(20), &mut original).unwrap();
+        assert_eq!(repaired, original);
+    }
+
+    #[test]
+    fn mirrored_flash_reports_records_corrupted_in_both_copies() {
+        // Given
+        let mut flash =
+            Mirror::<_, _, 20>::new(FakeFlash::new(Address(0)), FakeFlash::new(Address(0)));
+        flash.write(Offset(0), &[0x55; 16]).unwrap();
+        let (mut primary, mut secondary) = flash.into_inner();
+        primary.write(Address(0), &[0xAA]).unwrap();
+        secondary.write(Address(1), &[0xAA]).unwrap();
+        let mut flash = Mirror::<_, _, 20>::new(primary, secondary);
+
+        // When
+        let result = flash.read(Offset(0), &mut [0u8; 4]);
+
+        // Then
+        assert_eq!(result, Err(nb::Error::Other(MirrorError::Corrupted)));
+    }
+
+    #[test]
+    fn mirrored_flash_reads_erased_records_as_blank() {
+        // Given
+        let (mut primary, mut secondary) = (FakeFlash::new(Address(0)), FakeFlash::new(Address(0)));
+        primary.write(Address(0), &[0xFF; 40]).unwrap();
+        secondary.write(Address(0), &[0xFF; 40]).unwrap();
+        let mut flash = Mirror::<_, _, 20>::new(primary, secondary);
+
+        // When
+        let mut bytes = [0u8; 32];
+        flash.read(Offset(0), &mut bytes).unwrap();
+
+        // Then
+        assert_eq!(bytes, [0xFF; 32]);
+    }
+
+    #[test]
+    fn mirrored_flash_writes_each_record_with_its_crc_in_one_go() {
+        // Given
+        let primary = LoggingFlash(FakeFlash::new(Address(0)), Vec::new());
+        let secondary = LoggingFlash(FakeFlash::new(Address(0)), Vec::new());
+        let mut flash = Mirror::<_, _, 20>::new(primary, secondary);
+
+        // When
+        flash.write(Offset(0), &[0x55; 32]).unwrap();
+
+        // Then
+        let (primary, secondary) = flash.into_inner();
+        assert_eq!(primary.1, [(Address(0), 20), (Address(20), 20)]);
+        assert_eq!(secondary.1, [(Address(0), 20), (Address(20), 20)]);
+    }
+}
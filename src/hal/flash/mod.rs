@@ -0,0 +1,371 @@
+use crate::utilities::memory::Address;
+use core::{
+    cmp::min,
+    mem::{size_of, MaybeUninit},
+    ops::{Add, Sub},
+    slice,
+};
+
+mod concat;
+mod cursor;
+mod mirror;
+mod unaligned;
+mod wear_leveller;
+
+pub use concat::{Concat, ConcatError};
+pub use cursor::{CursorError, FlashCursor, SeekFrom};
+pub use mirror::{Mirror, MirrorError};
+pub use unaligned::Unaligned;
+pub use wear_leveller::{WearError, WearLeveller};
+
+/// Reads and writes a range of bytes, generic over an address
+pub trait ReadWrite {
+    type Error: Clone + Copy + Copy;
+    type Address: Address;
+
+    /// An identifying name for the flash chip.
+    fn label() -> &'static str;
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error>;
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error>;
+    fn range(&self) -> (Self::Address, Self::Address);
+    fn erase(&mut self) -> nb::Result<(), Self::Error>;
+    fn bytes(&mut self, address: Self::Address) -> ReadIterator<Self> {
+        ReadIterator {
+            reader: self,
+            address,
+            errored: false,
+            buffer: [0u8; ITERATOR_BUFFER_SIZE],
+            buffer_head: ITERATOR_BUFFER_SIZE,
+        }
+    }
+
+    /// A seekable reader bounded to this flash's `range()`.
+    fn cursor(&mut self) -> FlashCursor<'_, Self> {
+        let (start, end) = self.range();
+        FlashCursor::new(self, start, end)
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error>;
+}
+
+/// Streams chunks of any size into flash, buffering them into `BUFFER` sized
+/// transfers so the driver sees few, large writes (ideally one per erase unit).
+///
+/// The first failure is latched: later pushes are ignored and report it again.
+pub struct FlashWriter<'a, F: ReadWrite, const BUFFER: usize> {
+    flash: &'a mut F,
+    address: F::Address,
+    buffer: [u8; BUFFER],
+    buffered: usize,
+    error: Option<F::Error>,
+}
+
+impl<'a, F: ReadWrite, const BUFFER: usize> FlashWriter<'a, F, BUFFER> {
+    /// Starts a stream of writes at `address`.
+    pub fn new(flash: &'a mut F, address: F::Address) -> Self {
+        Self { flash, address, buffer: [0u8; BUFFER], buffered: 0, error: None }
+    }
+
+    /// Queues bytes after those previously pushed, writing every time the buffer fills.
+    pub fn push(&mut self, mut bytes: &[u8]) -> Result<(), F::Error> {
+        while !bytes.is_empty() {
+            if let Some(error) = self.error {
+                return Err(error);
+            }
+            let length = min(BUFFER - self.buffered, bytes.len());
+            self.buffer[self.buffered..self.buffered + length].copy_from_slice(&bytes[..length]);
+            self.buffered += length;
+            bytes = &bytes[length..];
+            if self.buffered == BUFFER {
+                self.flush();
+            }
+        }
+        self.error.map_or(Ok(()), Err)
+    }
+
+    /// Writes any bytes still buffered, reporting the first failure of the stream.
+    pub fn finish(mut self) -> Result<(), F::Error> {
+        if self.error.is_none() && self.buffered > 0 {
+            self.flush();
+        }
+        self.error.map_or(Ok(()), Err)
+    }
+
+    /// Pushes every block in turn and finishes the stream. This is the whole of
+    /// `ReadWrite::write_from_blocks` for most implementors.
+    pub fn write_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        mut self,
+        blocks: I,
+    ) -> Result<(), F::Error> {
+        for block in blocks {
+            self.push(&block)?;
+        }
+        self.finish()
+    }
+
+    fn flush(&mut self) {
+        let bytes = &self.buffer[..self.buffered];
+        match nb::block!(self.flash.write(self.address, bytes)) {
+            Ok(()) => {
+                self.address = self.address + self.buffered;
+                self.buffered = 0;
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+}
+
+/// Address into a composite flash, as an offset from the start of its unified range.
+#[derive(Default, Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
+pub struct Offset(pub usize);
+
+impl Add<usize> for Offset {
+    type Output = Self;
+    fn add(self, rhs: usize) -> Offset { Offset(self.0 + rhs) }
+}
+
+impl Sub<usize> for Offset {
+    type Output = Self;
+    fn sub(self, rhs: usize) -> Offset { Offset(self.0.saturating_sub(rhs)) }
+}
+
+impl Sub<Offset> for Offset {
+    type Output = usize;
+    fn sub(self, rhs: Offset) -> usize { self.0.saturating_sub(rhs.0) }
+}
+
+impl From<Offset> for usize {
+    fn from(offset: Offset) -> Self { offset.0 }
+}
+
+/// Flash capable of pausing an ongoing program or erase operation, so other
+/// operations (e.g. reads) can be served before resuming it.
+pub trait Suspend: ReadWrite {
+    /// Suspends the program or erase operation in progress, if any.
+    fn suspend(&mut self) -> nb::Result<(), Self::Error>;
+    /// Resumes a previously suspended program or erase operation, if any.
+    fn resume(&mut self) -> nb::Result<(), Self::Error>;
+    fn is_suspended(&self) -> bool;
+}
+
+/// Flash that can lock address ranges against program and erase operations in hardware.
+///
+/// Locks apply to whole protection units (e.g. sectors), so locking may cover
+/// more than the requested range. Likewise, unlocking may release more than
+/// requested on devices with coarse protection schemes.
+pub trait WriteProtect: ReadWrite {
+    fn lock_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error>;
+    fn unlock_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadOnlyError<E> {
+    Flash(E),
+    /// Writes and erases are not available through a read only view.
+    ReadOnly,
+}
+
+/// Read only view of a flash, which can be handed out without the risk of
+/// the holder programming or erasing anything.
+pub struct ReadOnly<F: ReadWrite> {
+    flash: F,
+}
+
+impl<F: ReadWrite> ReadOnly<F> {
+    pub fn new(flash: F) -> Self { Self { flash } }
+}
+
+impl<F: ReadWrite> ReadWrite for ReadOnly<F> {
+    type Error = ReadOnlyError<F::Error>;
+    type Address = F::Address;
+
+    fn label() -> &'static str { F::label() }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        self.flash.read(address, bytes).map_err(|error| match error {
+            nb::Error::Other(error) => nb::Error::Other(ReadOnlyError::Flash(error)),
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+        })
+    }
+
+    fn write(&mut self, _: Self::Address, _: &[u8]) -> nb::Result<(), Self::Error> {
+        Err(nb::Error::Other(ReadOnlyError::ReadOnly))
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { self.flash.range() }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        Err(nb::Error::Other(ReadOnlyError::ReadOnly))
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        _: Self::Address,
+        _: I,
+    ) -> Result<(), Self::Error> {
+        Err(ReadOnlyError::ReadOnly)
+    }
+}
+
+/// Serialize an object to flash.
+pub trait UnportableSerialize: ReadWrite {
+    /// # Safety
+    ///
+    /// This is a very raw serialization (the bytes are written as-is). Should be
+    /// only used with repr(C) types with no internal references. It *will break* if any change
+    /// to the struct to serialize is made between serialization and deserialization, and it
+    /// *will* cause undefined behaviour. Make sure to erase the flash whenever there is an
+    /// update to the serializable types.
+    unsafe fn serialize<T: Sized>(
+        &mut self,
+        item: &T,
+        address: Self::Address,
+    ) -> nb::Result<(), Self::Error> {
+        // Get a view into the raw bytes conforming T
+        let bytes = slice::from_raw_parts((item as *const T) as *const u8, size_of::<T>());
+        self.write(address, bytes)
+    }
+}
+impl<F: ReadWrite> UnportableSerialize for F {}
+
+/// Deserialize an object from flash.
+pub trait UnportableDeserialize: ReadWrite {
+    /// # Safety
+    ///
+    /// This is a very raw serialization (the bytes are written as-is). Should be
+    /// only used in repr(C) types. It *will break* if any change to the struct to serialize is
+    /// made between serialization and deserialization, and it *will* cause undefined
+    /// behaviour. Make sure to erase the flash whenever there is an update to the serializable
+    /// types.
+    unsafe fn deserialize<T: Sized>(
+        &mut self,
+        address: Self::Address,
+    ) -> nb::Result<T, Self::Error> {
+        // Create uninitialized T with a zero repr
+        let mut uninit: MaybeUninit<T> = MaybeUninit::uninit();
+        let bytes = slice::from_raw_parts_mut(uninit.as_mut_ptr() as *mut _, size_of::<T>());
+
+        // Read its byte representation into it
+        self.read(address, bytes)?;
+        Ok(uninit.assume_init())
+    }
+}
+impl<F: ReadWrite> UnportableDeserialize for F {}
+
+const ITERATOR_BUFFER_SIZE: usize = 2048;
+
+pub struct ReadIterator<'a, R: ReadWrite + ?Sized> {
+    reader: &'a mut R,
+    errored: bool,
+    buffer: [u8; ITERATOR_BUFFER_SIZE],
+    address: R::Address,
+    buffer_head: usize,
+}
+
+impl<'a, R: ReadWrite + ?Sized> Iterator for ReadIterator<'a, R> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.errored {
+            return None;
+        }
+
+        if self.buffer_head == ITERATOR_BUFFER_SIZE {
+            if nb::block!(self.reader.read(self.address, &mut self.buffer)).is_err() {
+                self.errored = true;
+                return None;
+            };
+            self.buffer_head = 0;
+            self.address = self.address + ITERATOR_BUFFER_SIZE;
+        }
+
+        let byte = self.buffer[self.buffer_head];
+        self.buffer_head += 1;
+        Some(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::flash::{Address, FakeFlash};
+
+    #[test]
+    fn iterating_over_fake_flash() {
+        let mut expected_bytes = Vec::new();
+        for i in 0..10000 {
+            expected_bytes.push(i as u8);
+        }
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), expected_bytes.as_slice()).unwrap();
+        let bytes: Vec<u8> = flash.bytes(Address(0)).take(10000).collect();
+        assert_eq!(expected_bytes, bytes);
+    }
+
+    #[test]
+    fn read_only_flash_reads_but_never_writes() {
+        // Given
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), &[1, 2, 3]).unwrap();
+        let mut flash = ReadOnly::new(flash);
+
+        // When
+        let mut bytes = [0u8; 3];
+        flash.read(Address(0), &mut bytes).unwrap();
+
+        // Then
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(flash.write(Address(0), &[0]), Err(nb::Error::Other(ReadOnlyError::ReadOnly)));
+        assert_eq!(flash.erase(), Err(nb::Error::Other(ReadOnlyError::ReadOnly)));
+        assert_eq!(
+            flash.write_from_blocks(Address(0), [[0u8; 4]].iter().copied()),
+            Err(ReadOnlyError::ReadOnly)
+        );
+    }
+
+    #[test]
+    fn flash_writer_streams_chunks_of_any_size() {
+        // Given
+        let expected: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut flash = FakeFlash::new(Address(0));
+
+        // When
+        let mut writer = FlashWriter::<_, 64>::new(&mut flash, Address(10));
+        for chunk in expected.chunks(7) {
+            writer.push(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // Then
+        let mut bytes = vec![0u8; expected.len()];
+        flash.read(Address(10), &mut bytes).unwrap();
+        assert_eq!(expected, bytes);
+    }
+
+    #[test]
+    fn flash_writer_reports_the_first_failure() {
+        // Given
+        let mut flash = FakeFlash::new(Address(100));
+        let mut writer = FlashWriter::<_, 4>::new(&mut flash, Address(0));
+
+        // When
+        let result = writer.push(&[0u8; 4]);
+
+        // Then
+        assert!(result.is_err());
+        assert!(writer.push(&[0u8; 1]).is_err());
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use super::{FlashWriter, ReadWrite};
+use core::cmp::min;
+
+/// Adapter for flash drivers that can only program whole words of `WORD` bytes.
+///
+/// Accepts writes at any offset and of any length. Partial words at the edges
+/// are read, patched and written back, while the aligned bulk is forwarded
+/// straight to the wrapped driver.
+pub struct Unaligned<F: ReadWrite, const WORD: usize> {
+    flash: F,
+}
+
+impl<F: ReadWrite, const WORD: usize> Unaligned<F, WORD> {
+    pub fn new(flash: F) -> Self { Self { flash } }
+    pub fn into_inner(self) -> F { self.flash }
+
+    fn write_partial_word(
+        &mut self,
+        word_address: F::Address,
+        offset: usize,
+        bytes: &[u8],
+    ) -> nb::Result<(), F::Error> {
+        let mut word = [0u8; WORD];
+        nb::block!(self.flash.read(word_address, &mut word))?;
+        word[offset..offset + bytes.len()].copy_from_slice(bytes);
+        nb::block!(self.flash.write(word_address, &word))?;
+        Ok(())
+    }
+}
+
+impl<F: ReadWrite, const WORD: usize> ReadWrite for Unaligned<F, WORD> {
+    type Error = F::Error;
+    type Address = F::Address;
+
+    fn label() -> &'static str { F::label() }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        self.flash.read(address, bytes)
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        let (mut address, mut bytes) = (address, bytes);
+
+        let offset = Into::<usize>::into(address) % WORD;
+        if offset != 0 {
+            let length = min(WORD - offset, bytes.len());
+            self.write_partial_word(address - offset, offset, &bytes[..length])?;
+            address = address + length;
+            bytes = &bytes[length..];
+        }
+
+        let bulk_length = bytes.len() - bytes.len() % WORD;
+        if bulk_length > 0 {
+            nb::block!(self.flash.write(address, &bytes[..bulk_length]))?;
+        }
+
+        let tail = &bytes[bulk_length..];
+        if !tail.is_empty() {
+            self.write_partial_word(address + bulk_length, 0, tail)?;
+        }
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { self.flash.range() }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> { self.flash.erase() }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        if Into::<usize>::into(address) % WORD == 0 && N % WORD == 0 {
+            return self.flash.write_from_blocks(address, blocks);
+        }
+        FlashWriter::<_, N>::new(self, address).write_blocks(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::{
+        error::FakeError,
+        flash::{Address, FakeFlash},
+    };
+
+    /// Fake flash that, like most MCU flash, only programs whole aligned words.
+    struct WordFlash(FakeFlash);
+
+    impl ReadWrite for WordFlash {
+        type Error = FakeError;
+        type Address = Address;
+
+        fn label() -> &'static str { "Word Flash" }
+
+        fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), FakeError> {
+            self.0.read(address, bytes)
+        }
+
+        fn write(&mut self, address: Address, bytes: &[u8]) -> nb::Result<(), FakeError> {
+            assert!(address.0 % 4 == 0 && bytes.len() % 4 == 0, "Misaligned write");
+            self.0.write(address, bytes)
+        }
+
+        fn range(&self) -> (Address, Address) { self.0.range() }
+
+        fn erase(&mut self) -> nb::Result<(), FakeError> { self.0.erase() }
+
+        fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+            &mut self,
+            address: Address,
+            blocks: I,
+        ) -> Result<(), FakeError> {
+            FlashWriter::<_, 4>::new(self, address).write_blocks(blocks)
+        }
+    }
+
+    #[test]
+    fn unaligned_writes_preserve_surrounding_bytes() {
+        // Given
+        let mut flash = Unaligned::<_, 4>::new(WordFlash(FakeFlash::new(Address(0))));
+        flash.write(Address(0), &[0xAA; 16]).unwrap();
+
+        // When
+        flash.write(Address(3), &[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        flash.write(Address(13), &[8]).unwrap();
+
+        // Then
+        let mut bytes = [0u8; 16];
+        flash.read(Address(0), &mut bytes).unwrap();
+        assert_eq!(bytes, [0xAA, 0xAA, 0xAA, 1, 2, 3, 4, 5, 6, 7, 0xAA, 0xAA, 0xAA, 8, 0xAA, 0xAA]);
+    }
+
+    #[test]
+    fn unaligned_block_writes_fall_back_to_word_sized_writes() {
+        // Given
+        let mut flash = Unaligned::<_, 4>::new(WordFlash(FakeFlash::new(Address(0))));
+        flash.write(Address(0), &[0xAA; 16]).unwrap();
+
+        // When
+        flash.write_from_blocks(Address(4), [[1, 2, 3, 4], [5, 6, 7, 8]].iter().copied()).unwrap();
+        flash.write_from_blocks(Address(13), [[9, 10]].iter().copied()).unwrap();
+
+        // Then
+        let mut bytes = [0u8; 16];
+        flash.read(Address(0), &mut bytes).unwrap();
+        assert_eq!(bytes, [0xAA, 0xAA, 0xAA, 0xAA, 1, 2, 3, 4, 5, 6, 7, 8, 0xAA, 9, 10, 0xAA]);
+    }
+}
@@ -0,0 +1,374 @@
+use super::{FlashWriter, Offset, ReadWrite};
+use core::cmp::min;
+use crc::crc32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WearError<E> {
+    Flash(E),
+    OutOfBounds,
+    /// Slots must outnumber logical blocks and fit a block and its header.
+    InvalidGeometry,
+    /// A mapped slot no longer passes its CRC check.
+    Corrupted,
+}
+
+mod slot_header {
+    pub const SIZE: usize = 20;
+    pub const MAGIC: u32 = 0x5745_4152;
+    pub const RETIRED: u32 = 0x0000_0000;
+}
+
+/// Header committing a slot's contents, written after its payload.
+///
+/// The CRC covers everything after the magic word plus the payload, so a slot
+/// can be retired by clearing its magic while keeping its erase count readable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct SlotHeader {
+    magic: u32,
+    block: u32,
+    sequence: u32,
+    erase_count: u32,
+    crc: u32,
+}
+
+impl SlotHeader {
+    fn from_bytes(bytes: [u8; slot_header::SIZE]) -> Self {
+        let word = |index: usize| {
+            u32::from_le_bytes([
+                bytes[index * 4],
+                bytes[index * 4 + 1],
+                bytes[index * 4 + 2],
+                bytes[index * 4 + 3],
+            ])
+        };
+        Self {
+            magic: word(0),
+            block: word(1),
+            sequence: word(2),
+            erase_count: word(3),
+            crc: word(4),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; slot_header::SIZE] {
+        let mut bytes = [0u8; slot_header::SIZE];
+        [self.magic, self.block, self.sequence, self.erase_count, self.crc]
+            .iter()
+            .zip(bytes.chunks_mut(4))
+            .for_each(|(word, chunk)| chunk.copy_from_slice(&word.to_le_bytes()));
+        bytes
+    }
+
+    fn checksum(&self, payload: &[u8]) -> u32 {
+        let fields = &self.to_bytes()[4..16];
+        crc32::update(crc32::checksum_ieee(fields), &crc32::IEEE_TABLE, payload)
+    }
+}
+
+/// Serial number comparison (RFC 1982), so ordering survives the sequence wrapping
+/// around as long as live copies are less than 2^31 writes apart.
+fn is_newer(sequence: u32, than: u32) -> bool { (sequence.wrapping_sub(than) as i32) > 0 }
+
+/// Wear levelling layer presenting `BLOCKS` logical blocks of `BLOCK` bytes,
+/// spread over `SLOTS` physical slots of a flash device.
+///
+/// Each write of a logical block goes to the least erased free slot, and is
+/// committed by writing the slot header last. Headers carry a sequence number,
+/// so after a power loss the newest fully written copy of each block wins and
+/// the remapping table is rebuilt on `mount`. `slot_size` should match the
+/// device's erase unit, so that rewriting a slot never disturbs its neighbours.
+pub struct WearLeveller<F: ReadWrite, const BLOCK: usize, const BLOCKS: usize, const SLOTS: usize> {
+    flash: F,
+    base: F::Address,
+    slot_size: usize,
+    table: [Option<usize>; BLOCKS],
+    erase_counts: [u32; SLOTS],
+    sequence: u32,
+}
+
+impl<F: ReadWrite, const BLOCK: usize, const BLOCKS: usize, const SLOTS: usize>
+    WearLeveller<F, BLOCK, BLOCKS, SLOTS>
+{
+    /// Scans the slots starting at `base`, rebuilding the remapping table and erase counters.
+    pub fn mount(
+        flash: F,
+        base: F::Address,
+        slot_size: usize,
+    ) -> Result<Self, WearError<F::Error>> {
+        if SLOTS <= BLOCKS || slot_size < BLOCK + slot_header::SIZE {
+            return Err(WearError::InvalidGeometry);
+        }
+        if base + SLOTS * slot_size > flash.range().1 {
+            return Err(WearError::OutOfBounds);
+        }
+
+        let mut leveller = Self {
+            flash,
+            base,
+            slot_size,
+            table: [None; BLOCKS],
+            erase_counts: [0; SLOTS],
+            sequence: 0,
+        };
+        let mut sequences = [0u32; BLOCKS];
+        let mut newest: Option<u32> = None;
+        let mut known_counts = [false; SLOTS];
+        let mut payload = [0u8; BLOCK];
+        for (slot, known_count) in known_counts.iter_mut().enumerate() {
+            let header = match leveller.read_slot(slot, &mut payload)? {
+                Some(header) => header,
+                None => continue,
+            };
+            leveller.erase_counts[slot] = header.erase_count;
+            *known_count = true;
+            let block = header.block as usize;
+            if header.magic != slot_header::MAGIC || block >= BLOCKS {
+                continue;
+            }
+            // Two copies only survive a power loss right after a write, where the newest wins.
+            match leveller.table[block] {
+                Some(_) if !is_newer(header.sequence, sequences[block]) => leveller.retire(slot)?,
+                previous => {
+                    if let Some(other) = previous {
+                        leveller.retire(other)?;
+                    }
+                    leveller.table[block] = Some(slot);
+                    sequences[block] = header.sequence;
+                }
+            }
+            if newest.map_or(true, |newest| is_newer(header.sequence, newest)) {
+                newest = Some(header.sequence);
+            }
+        }
+        leveller.sequence = newest.map_or(0, |newest| newest.wrapping_add(1));
+
+        // Slots whose header was lost can't be trusted to be fresh.
+        let highest_count = leveller.erase_counts.iter().copied().max().unwrap_or(0);
+        leveller
+            .erase_counts
+            .iter_mut()
+            .zip(known_counts.iter())
+            .filter(|(_, known)| !**known)
+            .for_each(|(count, _)| *count = highest_count);
+        Ok(leveller)
+    }
+
+    pub fn into_inner(self) -> F { self.flash }
+
+    /// Number of times a physical slot has been rewritten.
+    pub fn erase_count(&self, slot: usize) -> Option<u32> { self.erase_counts.get(slot).copied() }
+
+    fn slot_address(&self, slot: usize) -> F::Address { self.base + slot * self.slot_size }
+
+    /// Reads a slot's payload, returning its header if it passes the CRC check.
+    fn read_slot(
+        &mut self,
+        slot: usize,
+        payload: &mut [u8; BLOCK],
+    ) -> Result<Option<SlotHeader>, WearError<F::Error>> {
+        let address = self.slot_address(slot);
+        let mut bytes = [0u8; slot_header::SIZE];
+        nb::block!(self.flash.read(address, &mut bytes)).map_err(WearError::Flash)?;
+        nb::block!(self.flash.read(address + slot_header::SIZE, payload))
+            .map_err(WearError::Flash)?;
+        let header = SlotHeader::from_bytes(bytes);
+        let recognised = header.magic == slot_header::MAGIC || header.magic == slot_header::RETIRED;
+        Ok((recognised && header.checksum(payload) == header.crc).then_some(header))
+    }
+
+    fn read_block(
+        &mut self,
+        block: usize,
+        payload: &mut [u8; BLOCK],
+    ) -> Result<(), WearError<F::Error>> {
+        match self.table[block] {
+            Some(slot) => match self.read_slot(slot, payload)? {
+                Some(_) => Ok(()),
+                None => Err(WearError::Corrupted),
+            },
+            None => {
+                *payload = [0xFF; BLOCK];
+                Ok(())
+            }
+        }
+    }
+
+    fn write_block(
+        &mut self,
+        block: usize,
+        payload: &[u8; BLOCK],
+    ) -> Result<(), WearError<F::Error>> {
+        let slot = (0..SLOTS)
+            .filter(|slot| !self.table.contains(&Some(*slot)))
+            .min_by_key(|slot| self.erase_counts[*slot])
+            .ok_or(WearError::InvalidGeometry)?;
+        let erase_count = self.erase_counts[slot].saturating_add(1);
+        let mut header = SlotHeader {
+            magic: slot_header::MAGIC,
+            block: block as u32,
+            sequence: self.sequence,
+            erase_count,
+            crc: 0,
+        };
+        header.crc = header.checksum(payload);
+
+        let address = self.slot_address(slot);
+        nb::block!(self.flash.write(address + slot_header::SIZE, payload))
+            .map_err(WearError::Flash)?;
+        nb::block!(self.flash.write(address, &header.to_bytes())).map_err(WearError::Flash)?;
+
+        self.erase_counts[slot] = erase_count;
+        self.sequence = self.sequence.wrapping_add(1);
+        // The old copy is only retired once the new one is committed.
+        match self.table[block].replace(slot) {
+            Some(previous) => self.retire(previous),
+            None => Ok(()),
+        }
+    }
+
+    fn retire(&mut self, slot: usize) -> Result<(), WearError<F::Error>> {
+        let address = self.slot_address(slot);
+        nb::block!(self.flash.write(address, &slot_header::RETIRED.to_le_bytes()))
+            .map_err(WearError::Flash)
+    }
+}
+
+impl<F: ReadWrite, const BLOCK: usize, const BLOCKS: usize, const SLOTS: usize> ReadWrite
+    for WearLeveller<F, BLOCK, BLOCKS, SLOTS>
+{
+    type Error = WearError<F::Error>;
+    type Address = Offset;
+
+    fn label() -> &'static str { "Wear levelled flash" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        if address.0 + bytes.len() > BLOCKS * BLOCK {
+            return Err(nb::Error::Other(WearError::OutOfBounds));
+        }
+        let mut payload = [0u8; BLOCK];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = address.0 + done;
+            let (block, within) = (position / BLOCK, position % BLOCK);
+            let count = min(BLOCK - within, bytes.len() - done);
+            self.read_block(block, &mut payload)?;
+            bytes[done..done + count].copy_from_slice(&payload[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        if address.0 + bytes.len() > BLOCKS * BLOCK {
+            return Err(nb::Error::Other(WearError::OutOfBounds));
+        }
+        let mut payload = [0u8; BLOCK];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = address.0 + done;
+            let (block, within) = (position / BLOCK, position % BLOCK);
+            let count = min(BLOCK - within, bytes.len() - done);
+            if count < BLOCK {
+                self.read_block(block, &mut payload)?;
+            }
+            payload[within..within + count].copy_from_slice(&bytes[done..done + count]);
+            self.write_block(block, &payload)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { (Offset(0), Offset(BLOCKS * BLOCK)) }
+
+    /// Forgets every logical block, keeping the erase counters.
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        for block in 0..BLOCKS {
+            if let Some(slot) = self.table[block].take() {
+                self.retire(slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        FlashWriter::<_, BLOCK>::new(self, address).write_blocks(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::doubles::flash::{Address, FakeFlash};
+
+    type Leveller = WearLeveller<FakeFlash, 8, 2, 4>;
+    const SLOT_SIZE: usize = 64;
+
+    #[test]
+    fn wear_leveller_spreads_rewrites_across_slots() {
+        // Given
+        let mut flash = Leveller::mount(FakeFlash::new(Address(0)), Address(0), SLOT_SIZE).unwrap();
+
+        // When
+        for value in 0..12u8 {
+            flash.write(Offset(0), &[value; 8]).unwrap();
+        }
+
+        // Then
+        assert!((0..4).all(|slot| flash.erase_count(slot) == Some(3)));
+        let mut flash = Leveller::mount(flash.into_inner(), Address(0), SLOT_SIZE).unwrap();
+        let mut bytes = [0u8; 16];
+        flash.read(Offset(0), &mut bytes).unwrap();
+        assert_eq!(bytes[..8], [11; 8]);
+        assert_eq!(bytes[8..], [0xFF; 8]);
+        assert!((0..4).all(|slot| flash.erase_count(slot) == Some(3)));
+    }
+
+    #[test]
+    fn wear_leveller_ignores_writes_interrupted_before_commit() {
+        // Given
+        let mut flash = Leveller::mount(FakeFlash::new(Address(0)), Address(0), SLOT_SIZE).unwrap();
+        flash.write(Offset(2), &[1, 2, 3, 4, 5, 6]).unwrap();
+        let mut inner = flash.into_inner();
+
+        // When
+        inner.write(Address(SLOT_SIZE as u32 + 20), &[0xAA; 8]).unwrap(); // Payload, no header
+
+        // Then
+        let mut flash = Leveller::mount(inner, Address(0), SLOT_SIZE).unwrap();
+        let mut bytes = [0u8; 6];
+        flash.read(Offset(2), &mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn wear_leveller_keeps_the_newest_copy_across_sequence_wrap() {
+        // Given
+        let mut flash = Leveller::mount(FakeFlash::new(Address(0)), Address(0), SLOT_SIZE).unwrap();
+        flash.sequence = u32::MAX;
+        flash.write(Offset(0), &[1; 8]).unwrap(); // Slot 0, last sequence before wrapping
+        let mut header = [0u8; slot_header::SIZE];
+        flash.flash.read(Address(0), &mut header).unwrap();
+        flash.write(Offset(0), &[2; 8]).unwrap(); // Slot 1, first sequence after wrapping
+
+        // When
+        let mut inner = flash.into_inner();
+        inner.write(Address(0), &header).unwrap(); // Power lost before retiring slot 0
+        let mut flash = Leveller::mount(inner, Address(0), SLOT_SIZE).unwrap();
+
+        // Then
+        let mut bytes = [0u8; 8];
+        flash.read(Offset(0), &mut bytes).unwrap();
+        assert_eq!(bytes, [2; 8]);
+        assert_eq!(flash.sequence, 1);
+    }
+
+    #[test]
+    fn wear_leveller_rejects_invalid_geometry() {
+        let result = Leveller::mount(FakeFlash::new(Address(0)), Address(0), 20);
+        assert!(matches!(result, Err(WearError::InvalidGeometry)));
+    }
+}
@@ -6,6 +6,7 @@
 //! crate documentation for details.
 #![macro_use]
 
+use core::cmp::min;
 use nb::{self, block};
 
 pub trait ReadWrite: Read + Write {}
@@ -13,7 +14,7 @@ impl<T: Read + Write> ReadWrite for T {}
 
 pub use ufmt::uWrite as Write;
 
-use super::time::Milliseconds;
+use super::time::{Milliseconds, Now};
 
 /// UART read half
 pub trait Read {
@@ -35,6 +36,83 @@ pub trait TimeoutRead {
     fn bytes<T: Copy + Into<Milliseconds>>(&mut self, timeout: T) -> TimeoutReadIterator<Self, T> {
         TimeoutReadIterator { reader: self, errored: false, timeout }
     }
+
+    /// Fills `buffer`, waiting up to `timeout` for each byte.
+    fn read_exact<T: Copy + Into<Milliseconds>>(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: T,
+    ) -> Result<(), PartialRead<Self::Error>> {
+        for (received, slot) in buffer.iter_mut().enumerate() {
+            *slot = self.read(timeout).map_err(|error| PartialRead { received, error })?;
+        }
+        Ok(())
+    }
+
+    /// Reads into `buffer` up to and including `delimiter`, waiting up to `timeout`
+    /// for each byte. Returns the length read, which is the whole buffer if it
+    /// filled up before the delimiter arrived.
+    fn read_until<T: Copy + Into<Milliseconds>>(
+        &mut self,
+        delimiter: u8,
+        buffer: &mut [u8],
+        timeout: T,
+    ) -> Result<usize, PartialRead<Self::Error>> {
+        for (received, slot) in buffer.iter_mut().enumerate() {
+            *slot = self.read(timeout).map_err(|error| PartialRead { received, error })?;
+            if *slot == delimiter {
+                return Ok(received + 1);
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    /// Fills `buffer`, giving up once `deadline` has passed since the call, or
+    /// when no byte arrives for `inter_byte`. `C` measures the deadline.
+    fn read_with_deadline<C: Now, T: Copy + Into<Milliseconds>>(
+        &mut self,
+        buffer: &mut [u8],
+        deadline: T,
+        inter_byte: T,
+    ) -> Result<(), PartialRead<Self::Error>> {
+        let start = C::now();
+        let (Milliseconds(deadline), Milliseconds(inter_byte)) =
+            (deadline.into(), inter_byte.into());
+        for (received, slot) in buffer.iter_mut().enumerate() {
+            let Milliseconds(elapsed) = C::now() - start;
+            let timeout = Milliseconds(min(deadline.saturating_sub(elapsed), inter_byte));
+            *slot = self.read(timeout).map_err(|error| PartialRead { received, error })?;
+        }
+        Ok(())
+    }
+}
+
+/// A multi-byte read that stopped early, with the number of bytes
+/// that arrived before the error.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PartialRead<E> {
+    pub received: usize,
+    pub error: E,
+}
+
+/// UART write half for raw bytes. `Write` is layered on top of it by
+/// writing the UTF-8 encoding of strings and characters.
+pub trait WriteBytes {
+    type Error: Copy + Clone;
+
+    /// Writes a single byte
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error>;
+
+    /// Writes a slice of bytes, blocking until all are accepted
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        for byte in bytes {
+            block!(self.write_byte(*byte))?;
+        }
+        Ok(())
+    }
+
+    /// Ensures all written bytes have left the device
+    fn flush(&mut self) -> nb::Result<(), Self::Error>;
 }
 
 pub struct ReadIterator<'a, R: Read + ?Sized> {
@@ -137,18 +215,20 @@ mod test {
         pub write_record: Vec<u8>,
     }
 
-    impl Write for MockUsart {
+    impl WriteBytes for MockUsart {
         type Error = ();
 
-        fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
-            for byte in s.as_bytes() {
-                self.write_record.push(*byte);
-            }
+        fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            self.write_record.push(byte);
             Ok(())
         }
-        fn write_char(&mut self, c: char) -> Result<(), Self::Error> {
-            Ok(self.write_record.push(c as u8))
-        }
+        fn flush(&mut self) -> nb::Result<(), Self::Error> { Ok(()) }
+    }
+
+    impl Write for MockUsart {
+        type Error = ();
+
+        fn write_str(&mut self, s: &str) -> Result<(), Self::Error> { self.write_all(s.as_bytes()) }
     }
 
     impl Read for MockUsart {
@@ -158,7 +238,46 @@ mod test {
         fn read(&mut self) -> nb::Result<u8, Self::Error> { Ok(self.mock_value_to_read) }
     }
 
+    #[derive(Debug, Default)]
+    struct MockTimeoutReader {
+        pub to_read: VecDeque<u8>,
+        pub timeouts_requested: Vec<Milliseconds>,
+    }
+
+    impl TimeoutRead for MockTimeoutReader {
+        type Error = ();
+
+        fn read<T: Copy + Into<Milliseconds>>(&mut self, timeout: T) -> Result<u8, Self::Error> {
+            self.timeouts_requested.push(timeout.into());
+            self.to_read.pop_front().ok_or(())
+        }
+    }
+
+    thread_local!(static TICKS: Cell<u32> = const { Cell::new(0) });
+
+    /// Clock that advances a millisecond every time it's read.
+    struct SteppingClock;
+
+    #[derive(Copy, Clone)]
+    struct Tick(u32);
+
+    impl core::ops::Sub for Tick {
+        type Output = Milliseconds;
+        fn sub(self, other: Self) -> Milliseconds { Milliseconds(self.0 - other.0) }
+    }
+
+    impl core::ops::Add<Milliseconds> for Tick {
+        type Output = Self;
+        fn add(self, other: Milliseconds) -> Self { Tick(self.0 + other.0) }
+    }
+
+    impl Now for SteppingClock {
+        type I = Tick;
+        fn now() -> Tick { TICKS.with(|ticks| Tick(ticks.replace(ticks.get() + 1))) }
+    }
+
     use super::*;
+    use std::{cell::Cell, collections::VecDeque};
     use ufmt::{uwrite, uwriteln};
 
     #[test]
@@ -191,4 +310,73 @@ mod test {
         // Then
         assert_eq!(expected_message, mock_usart.write_record);
     }
+
+    #[test]
+    fn non_ascii_characters_are_written_as_utf8() {
+        // Given
+        let mut mock_usart = MockUsart::default();
+
+        // When
+        mock_usart.write_char('é').unwrap();
+        mock_usart.write_all(&[0x06, 0x15]).unwrap();
+
+        // Then
+        assert_eq!(vec![0xC3, 0xA9, 0x06, 0x15], mock_usart.write_record);
+    }
+
+    #[test]
+    fn read_exact_reports_bytes_received_before_timeout() {
+        // Given
+        let mut reader = MockTimeoutReader { to_read: vec![1, 2, 3].into(), ..Default::default() };
+        let mut buffer = [0u8; 2];
+
+        // When / Then
+        assert_eq!(Ok(()), reader.read_exact(&mut buffer, Milliseconds(10)));
+        assert_eq!([1, 2], buffer);
+        assert_eq!(
+            Err(PartialRead { received: 1, error: () }),
+            reader.read_exact(&mut buffer, Milliseconds(10))
+        );
+    }
+
+    #[test]
+    fn read_until_stops_after_delimiter_or_full_buffer() {
+        // Given
+        let mut reader = MockTimeoutReader {
+            to_read: b"ab\ncdefg".iter().cloned().collect(),
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 4];
+
+        // When / Then
+        assert_eq!(Ok(3), reader.read_until(b'\n', &mut buffer, Milliseconds(10)));
+        assert_eq!(b"ab\n", &buffer[..3]);
+        assert_eq!(Ok(4), reader.read_until(b'\n', &mut buffer, Milliseconds(10)));
+        assert_eq!(b"cdef", &buffer);
+        assert_eq!(
+            Err(PartialRead { received: 1, error: () }),
+            reader.read_until(b'\n', &mut buffer, Milliseconds(10))
+        );
+    }
+
+    #[test]
+    fn read_with_deadline_shortens_timeouts_as_the_deadline_approaches() {
+        // Given
+        let mut reader = MockTimeoutReader { to_read: vec![1, 2, 3].into(), ..Default::default() };
+        let mut buffer = [0u8; 4];
+
+        // When
+        let result = reader.read_with_deadline::<SteppingClock, _>(
+            &mut buffer,
+            Milliseconds(4),
+            Milliseconds(2),
+        );
+
+        // Then
+        assert_eq!(Err(PartialRead { received: 3, error: () }), result);
+        assert_eq!(
+            vec![Milliseconds(2), Milliseconds(2), Milliseconds(1), Milliseconds(0)],
+            reader.timeouts_requested
+        );
+    }
 }
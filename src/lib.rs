@@ -29,6 +29,7 @@ pub mod utilities {
     mod macros;
     pub mod memory;
     pub mod xmodem;
+    pub mod ymodem;
 }
 
 pub use paste;
@@ -1,5 +1,10 @@
 //! Utilities for manipulating memory buffers.
 
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 /// Collects an iterator into a mutable slice of its item type.
 pub trait CollectSlice: Iterator {
     fn collect_slice(&mut self, slice: &mut [Self::Item]) -> usize;
@@ -35,6 +40,71 @@ where
     }
 }
 
+/// Fixed capacity byte queue that can be shared between one producer and one
+/// consumer (e.g. an interrupt handler and the application) without locking.
+///
+/// Pushing from more than one context at a time, or popping from more than
+/// one context at a time, is not supported.
+pub struct RingBuffer<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    /// Next slot to pop, counted modulo `2 * N` to tell a full buffer from an empty one.
+    head: AtomicUsize,
+    /// Next slot to push, counted modulo `2 * N`.
+    tail: AtomicUsize,
+}
+
+// NOTE(Safety) Slots are only written by the producer before publishing them
+// through `tail`, and only read by the consumer before releasing them through `head`.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0u8; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize { N }
+
+    pub fn len(&self) -> usize {
+        let (head, tail) = (self.head.load(Ordering::Acquire), self.tail.load(Ordering::Acquire));
+        (tail + 2 * N - head) % (2 * N)
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    pub fn is_full(&self) -> bool { self.len() == N }
+
+    /// Queues a byte, handing it back if the buffer is full.
+    pub fn push(&self, byte: u8) -> Result<(), u8> {
+        if self.is_full() {
+            return Err(byte);
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        // NOTE(Safety) The consumer never reads this slot until `tail` moves past it.
+        unsafe { (*self.buffer.get())[tail % N] = byte };
+        self.tail.store((tail + 1) % (2 * N), Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the oldest byte in the buffer, if any.
+    pub fn pop(&self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        // NOTE(Safety) The producer never writes this slot until `head` moves past it.
+        let byte = unsafe { (*self.buffer.get())[head % N] };
+        self.head.store((head + 1) % (2 * N), Ordering::Release);
+        Some(byte)
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self { Self::new() }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -61,4 +131,26 @@ mod test {
         let to_collect: [Result<u8, ()>; 3] = [Ok(3), Ok(2), Ok(1)];
         assert_eq!(Ok(3), to_collect.iter().copied().try_collect_slice(&mut ints));
     }
+
+    #[test]
+    fn ring_buffer_queues_bytes_up_to_its_capacity() {
+        // Given
+        let buffer = RingBuffer::<3>::new();
+
+        // When
+        for round in 0..5u8 {
+            assert!(buffer.is_empty());
+            assert_eq!(Ok(()), buffer.push(round));
+            assert_eq!(Ok(()), buffer.push(round + 1));
+            assert_eq!(Ok(()), buffer.push(round + 2));
+
+            // Then
+            assert!(buffer.is_full());
+            assert_eq!(Err(0xAA), buffer.push(0xAA));
+            assert_eq!(Some(round), buffer.pop());
+            assert_eq!(Some(round + 1), buffer.pop());
+            assert_eq!(Some(round + 2), buffer.pop());
+            assert_eq!(None, buffer.pop());
+        }
+    }
 }
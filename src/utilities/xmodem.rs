@@ -1,71 +1,521 @@
-//! Xmodem protocol parser.
+//! Xmodem protocol parser, with receiver and sender state machines.
+//!
+//! Supports the original additive checksum with 128 byte `SOH` blocks, as well
+//! as CRC-16 and 1024 byte `STX` blocks (XMODEM-CRC and XMODEM-1K).
+//!
+//! # Example
+//! ```ignore
+//! let mut writer = FlashWriter::<_, 512>::new(&mut flash, address);
+//! let length = xmodem::Receiver::new().receive(&mut serial, |payload| writer.push(payload))?;
+//! writer.finish()?;
+//! ```
 
-use core::convert::TryInto;
+use crc::crc16;
 use nom::{
     branch::alt,
     bytes::streaming::{tag, take},
-    number::streaming::be_u8,
+    combinator::verify,
+    number::streaming::{be_u16, be_u8},
     IResult,
 };
 
-use crate::hal::time::Seconds;
+use crate::hal::{
+    serial::{TimeoutRead, WriteBytes},
+    time::{Milliseconds, Seconds},
+};
 
 pub const PAYLOAD_SIZE: usize = 128;
-pub const MAX_PACKET_SIZE: usize = 132;
+pub const LARGE_PAYLOAD_SIZE: usize = 1024;
+/// Size of an `SOH` packet with the additive checksum.
+pub const MAX_PACKET_SIZE: usize = 3 + PAYLOAD_SIZE + 1;
+/// Size of an `STX` packet with a CRC, the largest there is.
+pub const MAX_LARGE_PACKET_SIZE: usize = 3 + LARGE_PAYLOAD_SIZE + 2;
 pub const DEFAULT_TIMEOUT: Seconds = Seconds(3);
+/// Consecutive failed attempts before a transfer is abandoned.
+pub const DEFAULT_MAX_RETRIES: u8 = 10;
+/// Handshake attempts asking for CRC-16 before falling back to the additive checksum.
+pub const CRC_ATTEMPTS: u8 = 3;
+/// Fills the unused tail of the last block.
+pub const PADDING: u8 = 0x1A;
 
 pub const ACK: u8 = 0x06;
 pub const NAK: u8 = 0x15;
 pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
 pub const EOT: u8 = 0x04;
 pub const ETB: u8 = 0x17;
 pub const CAN: u8 = 0x18;
+/// Sent instead of `NAK` to start a transfer with CRC-16.
+pub const CRC_REQUEST: u8 = b'C';
+
+/// Block integrity check, negotiated during the handshake.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Checksum {
+    /// One byte sum of the payload
+    Additive,
+    /// Big endian CRC-16/XMODEM of the payload
+    Crc16,
+}
 
+/// A block of data, borrowing its payload from the packet it was parsed from.
 #[derive(Debug, Eq, PartialEq)]
-pub struct Chunk {
+pub struct Chunk<'a> {
     pub block_number: u8,
-    pub payload: [u8; PAYLOAD_SIZE],
+    /// Either `PAYLOAD_SIZE` or `LARGE_PAYLOAD_SIZE` bytes
+    pub payload: &'a [u8],
 }
 
 #[derive(Debug, Eq, PartialEq)]
-pub enum Message {
-    Chunk(Chunk),
+pub enum Message<'a> {
+    Chunk(Chunk<'a>),
     EndOfTransmission,
     EndOfTransmissionBlock,
     Cancel,
 }
 
-pub fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
-    alt((parse_chunk, parse_eot, parse_etb, parse_cancel))(input)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error<S, D> {
+    /// The serial port failed to write
+    Serial(S),
+    /// The data sink or source failed
+    Storage(D),
+    /// The other end cancelled the transfer
+    Cancelled,
+    /// The other end stopped responding, or kept sending corrupted packets
+    RetriesExhausted,
+    /// A block arrived that was neither the expected one nor a repeat
+    OutOfSequence,
+    /// A YMODEM file header didn't fit in a block
+    InvalidHeader,
+}
+
+/// What a receiver saw when waiting for the next message.
+pub(crate) enum Incoming<'a> {
+    Message(Message<'a>),
+    /// Nothing arrived in time
+    Timeout,
+    /// A packet arrived incomplete or failed its checks
+    Corrupted,
+    /// A stray byte between packets
+    Noise,
+}
+
+impl Checksum {
+    fn size(self) -> usize {
+        match self {
+            Checksum::Additive => 1,
+            Checksum::Crc16 => 2,
+        }
+    }
+}
+
+impl Chunk<'_> {
+    /// Writes the chunk as an `SOH` or `STX` packet depending on its size,
+    /// returning the packet length.
+    pub fn serialize(&self, checksum: Checksum, buffer: &mut [u8; MAX_LARGE_PACKET_SIZE]) -> usize {
+        let payload_size = self.payload.len();
+        assert!(payload_size == PAYLOAD_SIZE || payload_size == LARGE_PAYLOAD_SIZE);
+        buffer[0] = if payload_size == PAYLOAD_SIZE { SOH } else { STX };
+        buffer[1] = self.block_number;
+        buffer[2] = !self.block_number;
+        buffer[3..3 + payload_size].copy_from_slice(self.payload);
+        let check = &mut buffer[3 + payload_size..];
+        match checksum {
+            Checksum::Additive => check[0] = additive_checksum(self.payload),
+            Checksum::Crc16 => {
+                check[..2].copy_from_slice(&crc16_xmodem(self.payload).to_be_bytes())
+            }
+        }
+        3 + payload_size + checksum.size()
+    }
+}
+
+/// XMODEM receiver, driving a serial port that can time out reads and write bytes.
+pub struct Receiver {
+    timeout: Milliseconds,
+    max_retries: u8,
+    checksum: Checksum,
+}
+
+/// XMODEM sender, driving a serial port that can time out reads and write bytes.
+pub struct Sender {
+    timeout: Milliseconds,
+    max_retries: u8,
+    large_blocks: bool,
+    crc_only: bool,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT.into(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            checksum: Checksum::Crc16,
+        }
+    }
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT.into(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            large_blocks: false,
+            crc_only: false,
+        }
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn timeout<T: Into<Milliseconds>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Preferred checksum. With `Crc16`, the receiver falls back to the additive
+    /// checksum if the sender doesn't answer the first `CRC_ATTEMPTS` requests.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Receives a whole transfer, passing each new payload to `sink` in order.
+    /// Returns the number of bytes delivered, padding included.
+    pub fn receive<S, D>(
+        &mut self,
+        serial: &mut S,
+        mut sink: impl FnMut(&[u8]) -> Result<(), D>,
+    ) -> Result<usize, Error<<S as WriteBytes>::Error, D>>
+    where
+        S: TimeoutRead + WriteBytes,
+    {
+        let mut checksum = self.checksum;
+        let mut started = false;
+        let mut expected = 1u8;
+        let mut received = 0usize;
+        let mut failures = 0u8;
+        let mut reply = Some(start_request(checksum));
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+
+        loop {
+            if failures > self.max_retries {
+                cancel(serial)?;
+                return Err(Error::RetriesExhausted);
+            }
+            if let Some(reply) = reply.take() {
+                serial.write_all(&[reply]).map_err(Error::Serial)?;
+            }
+
+            match receive_message(serial, checksum, self.timeout, &mut packet) {
+                Incoming::Timeout => {
+                    failures += 1;
+                    if !started && checksum == Checksum::Crc16 && failures >= CRC_ATTEMPTS {
+                        checksum = Checksum::Additive;
+                    }
+                    reply = Some(if started { NAK } else { start_request(checksum) });
+                }
+                Incoming::Corrupted => {
+                    // Something arrived, so the sender already settled on a checksum
+                    started = true;
+                    failures += 1;
+                    reply = Some(NAK);
+                }
+                Incoming::Message(Message::Chunk(chunk)) if chunk.block_number == expected => {
+                    if let Err(error) = sink(chunk.payload) {
+                        cancel(serial)?;
+                        return Err(Error::Storage(error));
+                    }
+                    started = true;
+                    received += chunk.payload.len();
+                    expected = expected.wrapping_add(1);
+                    failures = 0;
+                    reply = Some(ACK);
+                }
+                // Our last ACK was lost, so the sender repeated the block
+                Incoming::Message(Message::Chunk(chunk))
+                    if chunk.block_number == expected.wrapping_sub(1) =>
+                {
+                    reply = Some(ACK)
+                }
+                Incoming::Message(Message::Chunk(_)) => {
+                    cancel(serial)?;
+                    return Err(Error::OutOfSequence);
+                }
+                Incoming::Message(Message::EndOfTransmission) => {
+                    serial.write_all(&[ACK]).map_err(Error::Serial)?;
+                    return Ok(received);
+                }
+                Incoming::Message(Message::Cancel) => return Err(Error::Cancelled),
+                Incoming::Message(Message::EndOfTransmissionBlock) | Incoming::Noise => {}
+            }
+        }
+    }
+}
+
+/// Waits for the next message, reading a whole packet into `packet` if one starts.
+/// Two `CAN` in a row are reported as a `Cancel` message.
+pub(crate) fn receive_message<'a, S: TimeoutRead>(
+    serial: &mut S,
+    checksum: Checksum,
+    timeout: Milliseconds,
+    packet: &'a mut [u8; MAX_LARGE_PACKET_SIZE],
+) -> Incoming<'a> {
+    let start = match serial.read(timeout) {
+        Ok(byte) => byte,
+        Err(_) => return Incoming::Timeout,
+    };
+
+    match start {
+        SOH | STX => {
+            let payload_size = if start == SOH { PAYLOAD_SIZE } else { LARGE_PAYLOAD_SIZE };
+            let packet = &mut packet[..3 + payload_size + checksum.size()];
+            packet[0] = start;
+            // A bad byte (overrun, framing error) still takes up its slot, so keep reading
+            // to stay in step with the sender. Two failures in a row mean the line went quiet.
+            let mut intact = true;
+            let mut failed_last = false;
+            for byte in packet[1..].iter_mut() {
+                match serial.read(timeout) {
+                    Ok(value) => {
+                        *byte = value;
+                        failed_last = false;
+                    }
+                    Err(_) if failed_last => return Incoming::Corrupted,
+                    Err(_) => {
+                        intact = false;
+                        failed_last = true;
+                    }
+                }
+            }
+            match parse(packet, checksum) {
+                Ok((_, message)) if intact => Incoming::Message(message),
+                _ => Incoming::Corrupted,
+            }
+        }
+        EOT => Incoming::Message(Message::EndOfTransmission),
+        CAN if is_cancelled(serial, timeout) => Incoming::Message(Message::Cancel),
+        _ => Incoming::Noise,
+    }
+}
+
+impl Sender {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn timeout<T: Into<Milliseconds>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sends 1024 byte blocks when the receiver asks for CRC-16, as XMODEM-1K
+    /// receivers do. Off by default, as not every XMODEM-CRC receiver accepts them.
+    pub fn large_blocks(mut self, large_blocks: bool) -> Self {
+        self.large_blocks = large_blocks;
+        self
+    }
+
+    /// Ignores NAK requests for additive checksums while waiting for the receiver
+    /// to start, as YMODEM only runs with CRC-16.
+    pub(crate) fn crc_only(mut self, crc_only: bool) -> Self {
+        self.crc_only = crc_only;
+        self
+    }
+
+    /// Sends everything `source` produces, until it returns an empty read. Returns
+    /// the number of bytes sent, padding excluded.
+    pub fn send<S, D>(
+        &mut self,
+        serial: &mut S,
+        mut source: impl FnMut(&mut [u8]) -> Result<usize, D>,
+    ) -> Result<usize, Error<<S as WriteBytes>::Error, D>>
+    where
+        S: TimeoutRead + WriteBytes,
+    {
+        let checksum = self.await_start(serial)?;
+        let sent = self.send_blocks(serial, checksum, &mut source)?;
+        self.transmit(serial, &[EOT])?;
+        Ok(sent)
+    }
+
+    /// Sends everything `source` produces as numbered blocks, starting at one.
+    pub(crate) fn send_blocks<S, D>(
+        &self,
+        serial: &mut S,
+        checksum: Checksum,
+        mut source: impl FnMut(&mut [u8]) -> Result<usize, D>,
+    ) -> Result<usize, Error<<S as WriteBytes>::Error, D>>
+    where
+        S: TimeoutRead + WriteBytes,
+    {
+        let block_size = if self.large_blocks && checksum == Checksum::Crc16 {
+            LARGE_PAYLOAD_SIZE
+        } else {
+            PAYLOAD_SIZE
+        };
+
+        let mut block_number = 1u8;
+        let mut sent = 0usize;
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        loop {
+            let mut payload = [PADDING; LARGE_PAYLOAD_SIZE];
+            let mut length = 0;
+            while length < block_size {
+                match source(&mut payload[length..block_size]) {
+                    Ok(0) => break,
+                    Ok(read) => length += read,
+                    Err(error) => {
+                        cancel(serial)?;
+                        return Err(Error::Storage(error));
+                    }
+                }
+            }
+            if length == 0 {
+                break;
+            }
+
+            // A short tail fits in a small block
+            let payload_size = if length <= PAYLOAD_SIZE { PAYLOAD_SIZE } else { block_size };
+            let chunk = Chunk { block_number, payload: &payload[..payload_size] };
+            let packet_size = chunk.serialize(checksum, &mut packet);
+            self.transmit(serial, &packet[..packet_size])?;
+            sent += length;
+            block_number = block_number.wrapping_add(1);
+            if length < block_size {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Waits for the receiver to ask for the first block, returning the checksum it asked for.
+    pub(crate) fn await_start<S, D>(
+        &self,
+        serial: &mut S,
+    ) -> Result<Checksum, Error<<S as WriteBytes>::Error, D>>
+    where
+        S: TimeoutRead + WriteBytes,
+    {
+        for _ in 0..=self.max_retries {
+            match TimeoutRead::read(serial, self.timeout) {
+                Ok(NAK) if !self.crc_only => return Ok(Checksum::Additive),
+                Ok(CRC_REQUEST) => return Ok(Checksum::Crc16),
+                Ok(CAN) if is_cancelled(serial, self.timeout) => return Err(Error::Cancelled),
+                _ => {}
+            }
+        }
+        Err(Error::RetriesExhausted)
+    }
+
+    /// Writes `bytes` until the receiver acknowledges them.
+    pub(crate) fn transmit<S, D>(
+        &self,
+        serial: &mut S,
+        bytes: &[u8],
+    ) -> Result<(), Error<<S as WriteBytes>::Error, D>>
+    where
+        S: TimeoutRead + WriteBytes,
+    {
+        for _ in 0..=self.max_retries {
+            serial.write_all(bytes).map_err(Error::Serial)?;
+            while let Ok(response) = TimeoutRead::read(serial, self.timeout) {
+                match response {
+                    ACK => return Ok(()),
+                    NAK => break,
+                    CAN if is_cancelled(serial, self.timeout) => return Err(Error::Cancelled),
+                    _ => {}
+                }
+            }
+        }
+        cancel(serial)?;
+        Err(Error::RetriesExhausted)
+    }
+}
+
+pub(crate) fn start_request(checksum: Checksum) -> u8 {
+    match checksum {
+        Checksum::Additive => NAK,
+        Checksum::Crc16 => CRC_REQUEST,
+    }
+}
+
+/// A single `CAN` may be line noise, so a transfer is only cancelled by two in a row.
+fn is_cancelled<S: TimeoutRead>(serial: &mut S, timeout: Milliseconds) -> bool {
+    matches!(serial.read(timeout), Ok(CAN))
+}
+
+pub(crate) fn cancel<S: WriteBytes, D>(serial: &mut S) -> Result<(), Error<S::Error, D>> {
+    serial.write_all(&[CAN, CAN]).map_err(Error::Serial)
+}
+
+fn additive_checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, no reflection, no inversion).
+///
+/// The `crc` crate only provides reflected, inverted CRCs, so this runs the reflected
+/// table for the mirrored polynomial (0x8408, shared with X.25) over mirrored bytes,
+/// undoing the inversion on the way in and out.
+pub fn crc16_xmodem(bytes: &[u8]) -> u16 {
+    let reflected = bytes
+        .iter()
+        .fold(!0u16, |crc, byte| crc16::update(crc, &crc16::X25_TABLE, &[byte.reverse_bits()]));
+    (!reflected).reverse_bits()
+}
+
+/// Parses a message with the additive checksum.
+pub fn parse_message(input: &[u8]) -> IResult<&[u8], Message<'_>> {
+    parse(input, Checksum::Additive)
 }
 
-fn parse_chunk(input: &[u8]) -> IResult<&[u8], Message> {
-    let (input, _) = tag(&[SOH])(input)?;
+/// Parses a message with the given checksum.
+pub fn parse(input: &[u8], checksum: Checksum) -> IResult<&[u8], Message<'_>> {
+    alt((move |input| parse_chunk(input, checksum), parse_eot, parse_etb, parse_cancel))(input)
+}
+
+fn parse_chunk(input: &[u8], checksum: Checksum) -> IResult<&[u8], Message<'_>> {
+    let (input, payload_size) = alt((
+        |input| Ok((tag(&[SOH])(input)?.0, PAYLOAD_SIZE)),
+        |input| Ok((tag(&[STX])(input)?.0, LARGE_PAYLOAD_SIZE)),
+    ))(input)?;
     let (input, block_number) = be_u8(input)?;
     let (input, _) = tag(&[!block_number])(input)?;
-    let (input, payload) = take(PAYLOAD_SIZE)(input)?;
-    let checksum: u8 = payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
-    let (input, _) = tag(&[checksum])(input)?;
-    Ok((input, Message::Chunk(Chunk { block_number, payload: payload.try_into().unwrap() })))
+    let (input, payload) = take(payload_size)(input)?;
+    let input = match checksum {
+        Checksum::Additive => tag(&[additive_checksum(payload)])(input)?.0,
+        Checksum::Crc16 => verify(be_u16, |crc| *crc == crc16_xmodem(payload))(input)?.0,
+    };
+    Ok((input, Message::Chunk(Chunk { block_number, payload })))
 }
 
-fn parse_eot(input: &[u8]) -> IResult<&[u8], Message> {
+fn parse_eot(input: &[u8]) -> IResult<&[u8], Message<'_>> {
     Ok((tag(&[EOT])(input)?.0, Message::EndOfTransmission))
 }
 
-fn parse_etb(input: &[u8]) -> IResult<&[u8], Message> {
+fn parse_etb(input: &[u8]) -> IResult<&[u8], Message<'_>> {
     Ok((tag(&[ETB])(input)?.0, Message::EndOfTransmissionBlock))
 }
 
-fn parse_cancel(input: &[u8]) -> IResult<&[u8], Message> {
+fn parse_cancel(input: &[u8]) -> IResult<&[u8], Message<'_>> {
     Ok((tag(&[CAN])(input)?.0, Message::Cancel))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use nom::Err::Incomplete;
-    const MAX_PACKET_SIZE: usize = 132;
+    use crate::hal::doubles::serial::{connected_pair, Impairments, LinkError, MockSerial};
+    use std::thread;
 
     fn write_test_packet(index: u8, payload_value: u8, buffer: &mut [u8]) {
         let checksum = (0..128).fold(0, |sum: u8, _| sum.wrapping_add(payload_value));
@@ -109,7 +559,7 @@ mod test {
         let expected_index = 7u8;
 
         assert_eq!(
-            Message::Chunk(Chunk { payload: expected_payload, block_number: expected_index }),
+            Message::Chunk(Chunk { payload: &expected_payload, block_number: expected_index }),
             message
         );
         assert_eq!(input.len(), 0);
@@ -131,16 +581,296 @@ mod test {
 
         let (input, message) = parse_message(&input).unwrap();
         assert_eq!(
-            Message::Chunk(Chunk { payload: [1u8; PAYLOAD_SIZE], block_number: 1 }),
+            Message::Chunk(Chunk { payload: &[1u8; PAYLOAD_SIZE], block_number: 1 }),
             message
         );
         let (input, message) = parse_message(&input).unwrap();
         assert_eq!(
-            Message::Chunk(Chunk { payload: [2u8; PAYLOAD_SIZE], block_number: 2 }),
+            Message::Chunk(Chunk { payload: &[2u8; PAYLOAD_SIZE], block_number: 2 }),
             message
         );
         let (input, message) = parse_message(&input).unwrap();
         assert_eq!(Message::EndOfTransmission, message);
         assert_eq!(input.len(), 0);
     }
+
+    fn test_packet(index: u8, payload_value: u8) -> [u8; MAX_PACKET_SIZE] {
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        write_test_packet(index, payload_value, &mut packet);
+        packet
+    }
+
+    #[test]
+    fn receiver_acknowledges_new_and_repeated_blocks_and_rejects_corrupted_ones() {
+        // Given
+        let mut corrupted = test_packet(2, 2);
+        corrupted[10] ^= 0xFF;
+        let mut serial = MockSerial::default();
+        for packet in [test_packet(1, 1), corrupted, test_packet(2, 2), test_packet(2, 2)].iter() {
+            serial.to_read.extend(packet.iter());
+        }
+        serial.to_read.push_back(EOT);
+        let mut payloads = Vec::new();
+
+        // When
+        let result = Receiver::new().checksum(Checksum::Additive).receive(&mut serial, |payload| {
+            payloads.push(payload.to_vec());
+            Ok::<(), ()>(())
+        });
+
+        // Then
+        assert_eq!(Ok(2 * PAYLOAD_SIZE), result);
+        assert_eq!(vec![vec![1u8; PAYLOAD_SIZE], vec![2u8; PAYLOAD_SIZE]], payloads);
+        assert_eq!(vec![NAK, ACK, NAK, ACK, ACK, ACK], serial.written);
+    }
+
+    #[test]
+    fn receiver_follows_block_numbers_past_wraparound() {
+        // Given
+        let blocks = 300usize;
+        let mut serial = MockSerial::default();
+        for block in 1..=blocks {
+            serial.to_read.extend(test_packet(block as u8, block as u8).iter());
+        }
+        serial.to_read.push_back(EOT);
+        let mut delivered = 0;
+
+        // When
+        let result = Receiver::new().checksum(Checksum::Additive).receive(&mut serial, |payload| {
+            delivered += 1;
+            assert_eq!(delivered as u8, payload[0]);
+            Ok::<(), ()>(())
+        });
+
+        // Then
+        assert_eq!(Ok(blocks * PAYLOAD_SIZE), result);
+        assert_eq!(blocks, delivered);
+    }
+
+    #[test]
+    fn receiver_gives_up_on_cancel_or_silence() {
+        // Given
+        let mut serial = MockSerial::default();
+        serial.to_read.extend(test_packet(1, 1).iter());
+        serial.to_read.extend([CAN, CAN].iter());
+
+        // When / Then
+        assert_eq!(
+            Err(Error::Cancelled),
+            Receiver::new().checksum(Checksum::Additive).receive(&mut serial, |_| Ok::<(), ()>(()))
+        );
+
+        // Given
+        let mut serial = MockSerial::default();
+
+        // When / Then
+        assert_eq!(
+            Err(Error::RetriesExhausted),
+            Receiver::new()
+                .checksum(Checksum::Additive)
+                .max_retries(2)
+                .receive(&mut serial, |_| Ok::<(), ()>(()))
+        );
+        assert_eq!(vec![NAK, NAK, NAK, CAN, CAN], serial.written);
+    }
+
+    #[test]
+    fn sender_pads_last_block_and_resends_rejected_packets() {
+        // Given
+        let data: Vec<u8> = (0..200u8).collect();
+        let mut remaining = &data[..];
+        let mut serial = MockSerial::default();
+        serial.to_read.extend([NAK, NAK, ACK, ACK, ACK].iter());
+
+        // When
+        let result = Sender::new().send(&mut serial, |buffer| {
+            let length = buffer.len().min(remaining.len());
+            buffer[..length].copy_from_slice(&remaining[..length]);
+            remaining = &remaining[length..];
+            Ok::<usize, ()>(length)
+        });
+
+        // Then
+        assert_eq!(Ok(200), result);
+        let packets: Vec<_> = serial.written.chunks(MAX_PACKET_SIZE).collect();
+        assert_eq!(4, packets.len());
+        assert_eq!(packets[0], packets[1]);
+        let (_, message) = parse_message(packets[2]).unwrap();
+        let mut expected_payload = [PADDING; PAYLOAD_SIZE];
+        expected_payload[..72].copy_from_slice(&data[128..]);
+        assert_eq!(Message::Chunk(Chunk { block_number: 2, payload: &expected_payload }), message);
+        assert_eq!(&[EOT], packets[3]);
+    }
+
+    fn large_crc_packet(index: u8, payload_value: u8) -> Vec<u8> {
+        let payload = [payload_value; LARGE_PAYLOAD_SIZE];
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        let length = Chunk { block_number: index, payload: &payload }
+            .serialize(Checksum::Crc16, &mut packet);
+        packet[..length].to_vec()
+    }
+
+    #[test]
+    fn crc16_matches_xmodem_check_value() {
+        assert_eq!(0x31C3, crc16_xmodem(b"123456789"));
+        assert_eq!(0x0000, crc16_xmodem(&[]));
+    }
+
+    #[test]
+    fn parsing_large_crc_chunk_and_rejecting_bad_crc() {
+        // Given
+        let mut packet = large_crc_packet(3, 9);
+
+        // When
+        let (input, message) = parse(&packet, Checksum::Crc16).unwrap();
+
+        // Then
+        assert_eq!(
+            Message::Chunk(Chunk { block_number: 3, payload: &[9u8; LARGE_PAYLOAD_SIZE] }),
+            message
+        );
+        assert_eq!(input.len(), 0);
+        assert_eq!(STX, packet[0]);
+        assert_eq!(3 + LARGE_PAYLOAD_SIZE + 2, packet.len());
+
+        // Given
+        *packet.last_mut().unwrap() ^= 1;
+
+        // When / Then
+        assert!(parse(&packet, Checksum::Crc16).is_err());
+    }
+
+    #[test]
+    fn receiver_requests_crc_and_accepts_large_blocks() {
+        // Given
+        let mut serial = MockSerial::default();
+        serial.to_read.extend(large_crc_packet(1, 1));
+        serial.to_read.extend(large_crc_packet(2, 2));
+        serial.to_read.push_back(EOT);
+        let mut payloads = Vec::new();
+
+        // When
+        let result = Receiver::new().receive(&mut serial, |payload| {
+            payloads.push(payload.to_vec());
+            Ok::<(), ()>(())
+        });
+
+        // Then
+        assert_eq!(Ok(2 * LARGE_PAYLOAD_SIZE), result);
+        assert_eq!(vec![vec![1u8; LARGE_PAYLOAD_SIZE], vec![2u8; LARGE_PAYLOAD_SIZE]], payloads);
+        assert_eq!(vec![CRC_REQUEST, ACK, ACK, ACK], serial.written);
+    }
+
+    #[test]
+    fn receiver_falls_back_to_additive_checksum() {
+        // Given
+        let mut serial = MockSerial::default();
+
+        // When
+        let result = Receiver::new().max_retries(4).receive(&mut serial, |_| Ok::<(), ()>(()));
+
+        // Then
+        assert_eq!(Err(Error::RetriesExhausted), result);
+        assert_eq!(vec![CRC_REQUEST, CRC_REQUEST, CRC_REQUEST, NAK, NAK, CAN, CAN], serial.written);
+    }
+
+    #[test]
+    fn sender_uses_large_crc_blocks_when_requested() {
+        // Given
+        let data = vec![5u8; LARGE_PAYLOAD_SIZE + 10];
+        let mut remaining = &data[..];
+        let mut serial = MockSerial::default();
+        serial.to_read.extend([CRC_REQUEST, ACK, ACK, ACK].iter());
+
+        // When
+        let result = Sender::new().large_blocks(true).send(&mut serial, |buffer| {
+            let length = buffer.len().min(remaining.len());
+            buffer[..length].copy_from_slice(&remaining[..length]);
+            remaining = &remaining[length..];
+            Ok::<usize, ()>(length)
+        });
+
+        // Then
+        assert_eq!(Ok(data.len()), result);
+        let (input, first) = parse(&serial.written, Checksum::Crc16).unwrap();
+        assert_eq!(
+            Message::Chunk(Chunk { block_number: 1, payload: &data[..LARGE_PAYLOAD_SIZE] }),
+            first
+        );
+        let (input, second) = parse(input, Checksum::Crc16).unwrap();
+        let mut tail = [PADDING; PAYLOAD_SIZE];
+        tail[..10].copy_from_slice(&data[LARGE_PAYLOAD_SIZE..]);
+        assert_eq!(Message::Chunk(Chunk { block_number: 2, payload: &tail }), second);
+        assert_eq!(&[EOT], input);
+    }
+
+    type PairError = Error<LinkError, ()>;
+
+    /// Sends `data` from one end of a connected pair to the other, returning
+    /// what both sides reported and what the receiver delivered.
+    fn transfer_over_pair(
+        data: &[u8],
+        sender_impairments: Impairments,
+        receiver_impairments: Impairments,
+    ) -> (Result<usize, PairError>, Result<usize, PairError>, Vec<u8>) {
+        let (mut sender_end, mut receiver_end) = connected_pair();
+        sender_end.impair_outgoing(sender_impairments);
+        receiver_end.impair_outgoing(receiver_impairments);
+        let mut received = vec![];
+        // Like real senders, wait longer than the receiver so retries don't cross
+        let (sender_timeout, receiver_timeout) = (Milliseconds(100), Milliseconds(20));
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || {
+                let mut remaining = data;
+                Sender::new().timeout(sender_timeout).send(&mut sender_end, |buffer| {
+                    let length = buffer.len().min(remaining.len());
+                    buffer[..length].copy_from_slice(&remaining[..length]);
+                    remaining = &remaining[length..];
+                    Ok(length)
+                })
+            });
+            let received_result =
+                Receiver::new().timeout(receiver_timeout).receive(&mut receiver_end, |block| {
+                    received.extend_from_slice(block);
+                    Ok(())
+                });
+            (sender.join().unwrap(), received_result, received)
+        })
+    }
+
+    #[test]
+    fn transfer_between_connected_endpoints() {
+        // Given
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        // When
+        let (sent, received, output) =
+            transfer_over_pair(&data, Impairments::new(), Impairments::new());
+
+        // Then
+        assert_eq!(Ok(data.len()), sent);
+        assert_eq!(Ok(1024), received);
+        assert_eq!(&data[..], &output[..data.len()]);
+        assert!(output[data.len()..].iter().all(|byte| *byte == PADDING));
+    }
+
+    #[test]
+    fn transfer_recovers_from_line_impairments() {
+        // Given
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let noisy_line = Impairments::new()
+            .drop_byte(50)
+            .flip(200, 0x10)
+            .error(400, LinkError::Overrun)
+            .latency(Milliseconds(1));
+        let lossy_replies = Impairments::new().drop_byte(2);
+
+        // When
+        let (sent, received, output) = transfer_over_pair(&data, noisy_line, lossy_replies);
+
+        // Then
+        assert_eq!(Ok(data.len()), sent);
+        assert_eq!(Ok(1024), received);
+        assert_eq!(&data[..], &output[..data.len()]);
+    }
 }
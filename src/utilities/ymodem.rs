@@ -0,0 +1,605 @@
+//! YMODEM batch transfers, built on top of XMODEM-CRC.
+//!
+//! Each file is preceded by block 0, which carries its name, size and
+//! modification time. Received files are truncated to their exact size
+//! rather than padded, and an empty block 0 closes the batch.
+//!
+//! # Example
+//! ```ignore
+//! struct Image<'a> { writer: FlashWriter<'a, Flash, 512> }
+//! impl BatchSink for Image<'_> {
+//!     type Error = FlashError;
+//!     fn start(&mut self, file: &FileInfo) -> Result<(), FlashError> { /* reject if too big, pre-erase */ }
+//!     fn write(&mut self, data: &[u8]) -> Result<(), FlashError> { self.writer.push(data) }
+//!     fn finish(&mut self) -> Result<(), FlashError> { Ok(()) }
+//! }
+//! let files = ymodem::Receiver::new().receive(&mut serial, &mut image)?;
+//! ```
+
+use core::{cmp::min, fmt::Write};
+
+use crate::{
+    hal::{
+        serial::{TimeoutRead, WriteBytes},
+        time::Milliseconds,
+    },
+    utilities::xmodem::{
+        self, cancel, receive_message, Checksum, Chunk, Error, Incoming, Message, ACK, CRC_REQUEST,
+        DEFAULT_MAX_RETRIES, DEFAULT_TIMEOUT, LARGE_PAYLOAD_SIZE, MAX_LARGE_PACKET_SIZE, NAK,
+        PAYLOAD_SIZE,
+    },
+};
+
+/// File metadata carried by block 0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FileInfo<'a> {
+    pub name: &'a str,
+    /// Exact length in bytes
+    pub size: Option<usize>,
+    /// Seconds since the Unix epoch. Can only be sent along with the size.
+    pub modified: Option<u32>,
+}
+
+/// Contents of block 0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Header<'a> {
+    File(FileInfo<'a>),
+    /// An empty block 0, closing the batch
+    EndOfBatch,
+}
+
+type BatchError<S, K> = Error<<S as WriteBytes>::Error, <K as BatchSink>::Error>;
+
+/// Destination for received files.
+pub trait BatchSink {
+    type Error;
+
+    /// A file is about to arrive. Returning an error rejects it, cancelling the session.
+    fn start(&mut self, file: &FileInfo<'_>) -> Result<(), Self::Error>;
+    /// The next piece of the current file.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+    /// The current file has arrived completely.
+    fn finish(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Origin of sent files.
+pub trait BatchSource {
+    type Error;
+
+    /// Moves on to the next file, returning its metadata, or `None` once the batch is over.
+    fn next_file(&mut self) -> Result<Option<FileInfo<'_>>, Self::Error>;
+    /// Reads from the current file. An empty read marks its end.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// YMODEM receiver, driving a serial port that can time out reads and write bytes.
+pub struct Receiver {
+    timeout: Milliseconds,
+    max_retries: u8,
+}
+
+/// YMODEM sender, driving a serial port that can time out reads and write bytes.
+pub struct Sender {
+    xmodem: xmodem::Sender,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self { timeout: DEFAULT_TIMEOUT.into(), max_retries: DEFAULT_MAX_RETRIES }
+    }
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Self { xmodem: xmodem::Sender::new().large_blocks(true).crc_only(true) }
+    }
+}
+
+impl FileInfo<'_> {
+    /// Writes the block 0 payload for this file, returning the payload size.
+    /// Returns `None` if the name is too long to fit in a block, or if it's empty
+    /// or contains a NUL, as the header would then be misread. The same goes for a
+    /// modification time without a size, as fields are identified by position.
+    pub fn serialize(&self, payload: &mut [u8; LARGE_PAYLOAD_SIZE]) -> Option<usize> {
+        if self.name.is_empty() || self.name.contains('\0') {
+            return None;
+        }
+        if self.modified.is_some() && self.size.is_none() {
+            return None;
+        }
+        payload.iter_mut().for_each(|byte| *byte = 0);
+        let mut writer = SliceWriter { buffer: payload, position: 0 };
+        write!(writer, "{}\0", self.name).ok()?;
+        if let Some(size) = self.size {
+            write!(writer, "{}", size).ok()?;
+            if let Some(modified) = self.modified {
+                write!(writer, " {:o}", modified).ok()?;
+            }
+        }
+        // Keep at least one terminating NUL
+        match writer.position {
+            length if length < PAYLOAD_SIZE => Some(PAYLOAD_SIZE),
+            length if length < LARGE_PAYLOAD_SIZE => Some(LARGE_PAYLOAD_SIZE),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a block 0 payload: a NUL terminated name, followed by the decimal size
+/// and octal modification time, separated by spaces. Later fields are ignored.
+pub fn parse_header(payload: &[u8]) -> Option<Header<'_>> {
+    let name_length = payload.iter().position(|byte| *byte == 0)?;
+    if name_length == 0 {
+        return Some(Header::EndOfBatch);
+    }
+    let name = core::str::from_utf8(&payload[..name_length]).ok()?;
+    let mut fields = payload[name_length + 1..].split(|byte| *byte == b' ' || *byte == 0);
+    let size = fields.next().and_then(|field| parse_number(field, 10));
+    let modified = size.and(fields.next().and_then(|field| parse_number(field, 8)));
+    Some(Header::File(FileInfo { name, size: size.map(|size| size as usize), modified }))
+}
+
+fn parse_number(field: &[u8], radix: u32) -> Option<u32> {
+    core::str::from_utf8(field).ok().and_then(|field| u32::from_str_radix(field, radix).ok())
+}
+
+struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.position + s.len();
+        self.buffer
+            .get_mut(self.position..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.position = end;
+        Ok(())
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn timeout<T: Into<Milliseconds>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Receives a whole batch into `sink`, returning the number of files received.
+    pub fn receive<S, K>(&mut self, serial: &mut S, sink: &mut K) -> Result<usize, BatchError<S, K>>
+    where
+        S: TimeoutRead + WriteBytes,
+        K: BatchSink,
+    {
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        let mut files = 0;
+        loop {
+            match self.receive_header(serial, sink, &mut packet)? {
+                Some(size) => self.receive_file(serial, sink, size, &mut packet)?,
+                None => return Ok(files),
+            }
+            files += 1;
+        }
+    }
+
+    /// Waits for block 0, returning the announced file size, or `None` at the end of the batch.
+    fn receive_header<S, K>(
+        &self,
+        serial: &mut S,
+        sink: &mut K,
+        packet: &mut [u8; MAX_LARGE_PACKET_SIZE],
+    ) -> Result<Option<Option<usize>>, BatchError<S, K>>
+    where
+        S: TimeoutRead + WriteBytes,
+        K: BatchSink,
+    {
+        let mut failures = 0u8;
+        let mut reply = Some(CRC_REQUEST);
+        loop {
+            if failures > self.max_retries {
+                cancel(serial)?;
+                return Err(Error::RetriesExhausted);
+            }
+            if let Some(reply) = reply.take() {
+                serial.write_all(&[reply]).map_err(Error::Serial)?;
+            }
+
+            match receive_message(serial, Checksum::Crc16, self.timeout, packet) {
+                Incoming::Timeout => {
+                    failures += 1;
+                    reply = Some(CRC_REQUEST);
+                }
+                Incoming::Message(Message::Chunk(chunk)) if chunk.block_number == 0 => {
+                    match parse_header(chunk.payload) {
+                        Some(Header::File(file)) => {
+                            if let Err(error) = sink.start(&file) {
+                                cancel(serial)?;
+                                return Err(Error::Storage(error));
+                            }
+                            serial.write_all(&[ACK]).map_err(Error::Serial)?;
+                            return Ok(Some(file.size));
+                        }
+                        Some(Header::EndOfBatch) => {
+                            serial.write_all(&[ACK]).map_err(Error::Serial)?;
+                            return Ok(None);
+                        }
+                        None => {
+                            failures += 1;
+                            reply = Some(NAK);
+                        }
+                    }
+                }
+                Incoming::Corrupted => {
+                    failures += 1;
+                    reply = Some(NAK);
+                }
+                // The sender missed our final ACK for the previous file
+                Incoming::Message(Message::EndOfTransmission) => reply = Some(ACK),
+                Incoming::Message(Message::Cancel) => return Err(Error::Cancelled),
+                Incoming::Message(Message::Chunk(_)) => {
+                    cancel(serial)?;
+                    return Err(Error::OutOfSequence);
+                }
+                Incoming::Message(Message::EndOfTransmissionBlock) | Incoming::Noise => {}
+            }
+        }
+    }
+
+    /// Receives the data blocks of a file, truncating the last one to `size` if known.
+    fn receive_file<S, K>(
+        &self,
+        serial: &mut S,
+        sink: &mut K,
+        mut size: Option<usize>,
+        packet: &mut [u8; MAX_LARGE_PACKET_SIZE],
+    ) -> Result<(), BatchError<S, K>>
+    where
+        S: TimeoutRead + WriteBytes,
+        K: BatchSink,
+    {
+        let mut expected = 1u8;
+        let mut failures = 0u8;
+        let mut end_seen = false;
+        let mut reply = Some(CRC_REQUEST);
+        loop {
+            if failures > self.max_retries {
+                cancel(serial)?;
+                return Err(Error::RetriesExhausted);
+            }
+            if let Some(reply) = reply.take() {
+                serial.write_all(&[reply]).map_err(Error::Serial)?;
+            }
+
+            match receive_message(serial, Checksum::Crc16, self.timeout, packet) {
+                Incoming::Timeout => {
+                    failures += 1;
+                    reply = Some(if expected == 1 { CRC_REQUEST } else { NAK });
+                }
+                Incoming::Corrupted => {
+                    failures += 1;
+                    reply = Some(NAK);
+                }
+                Incoming::Message(Message::Chunk(chunk)) if chunk.block_number == expected => {
+                    let length =
+                        size.map_or(chunk.payload.len(), |size| min(size, chunk.payload.len()));
+                    size = size.map(|size| size - length);
+                    if let Err(error) = sink.write(&chunk.payload[..length]) {
+                        cancel(serial)?;
+                        return Err(Error::Storage(error));
+                    }
+                    expected = expected.wrapping_add(1);
+                    failures = 0;
+                    reply = Some(ACK);
+                }
+                // Our last ACK was lost, so the sender repeated the block (or block 0)
+                Incoming::Message(Message::Chunk(chunk))
+                    if chunk.block_number == expected.wrapping_sub(1) =>
+                {
+                    reply = Some(ACK)
+                }
+                Incoming::Message(Message::Chunk(_)) => {
+                    cancel(serial)?;
+                    return Err(Error::OutOfSequence);
+                }
+                // The first EOT is refused, to make sure it wasn't line noise
+                Incoming::Message(Message::EndOfTransmission) if !end_seen => {
+                    end_seen = true;
+                    reply = Some(NAK);
+                }
+                Incoming::Message(Message::EndOfTransmission) => {
+                    if let Err(error) = sink.finish() {
+                        cancel(serial)?;
+                        return Err(Error::Storage(error));
+                    }
+                    serial.write_all(&[ACK]).map_err(Error::Serial)?;
+                    return Ok(());
+                }
+                Incoming::Message(Message::Cancel) => return Err(Error::Cancelled),
+                Incoming::Message(Message::EndOfTransmissionBlock) | Incoming::Noise => {}
+            }
+        }
+    }
+}
+
+impl Sender {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn timeout<T: Into<Milliseconds>>(mut self, timeout: T) -> Self {
+        self.xmodem = self.xmodem.timeout(timeout);
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u8) -> Self {
+        self.xmodem = self.xmodem.max_retries(max_retries);
+        self
+    }
+
+    /// Sends every file `source` offers, returning the number of files sent.
+    pub fn send<S, K>(
+        &mut self,
+        serial: &mut S,
+        source: &mut K,
+    ) -> Result<usize, Error<<S as WriteBytes>::Error, K::Error>>
+    where
+        S: TimeoutRead + WriteBytes,
+        K: BatchSource,
+    {
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        let mut files = 0;
+        loop {
+            let checksum = self.xmodem.await_start(serial)?;
+            let mut payload = [0u8; LARGE_PAYLOAD_SIZE];
+            let (payload_size, end_of_batch) = match source.next_file() {
+                Ok(Some(file)) => match file.serialize(&mut payload) {
+                    Some(payload_size) => (payload_size, false),
+                    None => {
+                        cancel(serial)?;
+                        return Err(Error::InvalidHeader);
+                    }
+                },
+                Ok(None) => (PAYLOAD_SIZE, true),
+                Err(error) => {
+                    cancel(serial)?;
+                    return Err(Error::Storage(error));
+                }
+            };
+
+            let header = Chunk { block_number: 0, payload: &payload[..payload_size] };
+            let packet_size = header.serialize(checksum, &mut packet);
+            self.xmodem.transmit(serial, &packet[..packet_size])?;
+            if end_of_batch {
+                return Ok(files);
+            }
+
+            let checksum = self.xmodem.await_start(serial)?;
+            self.xmodem.send_blocks(serial, checksum, |buffer| source.read(buffer))?;
+            self.xmodem.transmit(serial, &[xmodem::EOT])?;
+            files += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{hal::doubles::serial::MockSerial, utilities::xmodem::parse};
+
+    #[derive(Default)]
+    struct RecordingSink {
+        files: Vec<(String, Option<usize>, Vec<u8>)>,
+        finished: usize,
+        size_limit: Option<usize>,
+    }
+
+    impl BatchSink for RecordingSink {
+        type Error = ();
+
+        fn start(&mut self, file: &FileInfo<'_>) -> Result<(), ()> {
+            match (self.size_limit, file.size) {
+                (Some(limit), Some(size)) if size > limit => Err(()),
+                _ => {
+                    self.files.push((file.name.to_string(), file.size, vec![]));
+                    Ok(())
+                }
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.files.last_mut().unwrap().2.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), ()> {
+            self.finished += 1;
+            Ok(())
+        }
+    }
+
+    fn packet(block_number: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        let length = Chunk { block_number, payload }.serialize(Checksum::Crc16, &mut packet);
+        packet[..length].to_vec()
+    }
+
+    fn header(file: Option<FileInfo>) -> Vec<u8> {
+        let mut payload = [0u8; LARGE_PAYLOAD_SIZE];
+        let size = file.map_or(PAYLOAD_SIZE, |file| file.serialize(&mut payload).unwrap());
+        packet(0, &payload[..size])
+    }
+
+    #[test]
+    fn file_headers_round_trip() {
+        // Given
+        let file = FileInfo { name: "image.bin", size: Some(1234), modified: Some(0o13713337) };
+        let mut payload = [0u8; LARGE_PAYLOAD_SIZE];
+
+        // When
+        let size = file.serialize(&mut payload).unwrap();
+
+        // Then
+        assert_eq!(PAYLOAD_SIZE, size);
+        assert_eq!(b"image.bin\x001234 13713337\x00", &payload[..24]);
+        assert_eq!(Some(Header::File(file)), parse_header(&payload[..size]));
+        assert_eq!(Some(Header::EndOfBatch), parse_header(&[0u8; PAYLOAD_SIZE]));
+        let long_name = "x".repeat(LARGE_PAYLOAD_SIZE);
+        assert_eq!(
+            None,
+            FileInfo { name: &long_name, size: None, modified: None }.serialize(&mut payload)
+        );
+        assert_eq!(
+            None,
+            FileInfo { name: "undated.bin", size: None, modified: Some(1) }.serialize(&mut payload)
+        );
+    }
+
+    #[test]
+    fn receiver_truncates_files_to_their_announced_size() {
+        // Given
+        let mut serial = MockSerial::default();
+        let file = FileInfo { name: "a.bin", size: Some(130), modified: None };
+        serial.to_read.extend(header(Some(file)));
+        serial.to_read.extend(packet(1, &[1u8; PAYLOAD_SIZE]));
+        serial.to_read.extend(packet(2, &[2u8; PAYLOAD_SIZE]));
+        serial.to_read.extend([xmodem::EOT, xmodem::EOT].iter());
+        serial.to_read.extend(header(None));
+        let mut sink = RecordingSink::default();
+
+        // When
+        let result = Receiver::new().receive(&mut serial, &mut sink);
+
+        // Then
+        assert_eq!(Ok(1), result);
+        let mut expected_data = vec![1u8; PAYLOAD_SIZE];
+        expected_data.extend_from_slice(&[2, 2]);
+        assert_eq!(vec![("a.bin".to_string(), Some(130), expected_data)], sink.files);
+        assert_eq!(1, sink.finished);
+        assert_eq!(
+            vec![CRC_REQUEST, ACK, CRC_REQUEST, ACK, ACK, NAK, ACK, CRC_REQUEST, ACK],
+            serial.written
+        );
+    }
+
+    #[test]
+    fn receiver_cancels_when_sink_rejects_a_file() {
+        // Given
+        let mut serial = MockSerial::default();
+        let file = FileInfo { name: "huge.bin", size: Some(1 << 20), modified: None };
+        serial.to_read.extend(header(Some(file)));
+        let mut sink = RecordingSink { size_limit: Some(1 << 16), ..Default::default() };
+
+        // When
+        let result = Receiver::new().receive(&mut serial, &mut sink);
+
+        // Then
+        assert_eq!(Err(Error::Storage(())), result);
+        assert_eq!(vec![CRC_REQUEST, xmodem::CAN, xmodem::CAN], serial.written);
+    }
+
+    struct SliceSource<'a> {
+        files: &'a [(&'a str, &'a [u8])],
+        current: Option<&'a [u8]>,
+    }
+
+    impl BatchSource for SliceSource<'_> {
+        type Error = ();
+
+        fn next_file(&mut self) -> Result<Option<FileInfo<'_>>, ()> {
+            let (&(name, data), rest) = match self.files.split_first() {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+            self.files = rest;
+            self.current = Some(data);
+            Ok(Some(FileInfo { name, size: Some(data.len()), modified: None }))
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+            let data = self.current.as_mut().unwrap();
+            let length = min(buffer.len(), data.len());
+            buffer[..length].copy_from_slice(&data[..length]);
+            *data = &data[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn sender_announces_each_file_and_closes_the_batch() {
+        // Given
+        let data = [7u8; 10];
+        let mut source = SliceSource { files: &[("seven.bin", &data)], current: None };
+        let mut serial = MockSerial::default();
+        serial
+            .to_read
+            .extend([CRC_REQUEST, ACK, CRC_REQUEST, ACK, NAK, ACK, CRC_REQUEST, ACK].iter());
+
+        // When
+        let result = Sender::new().send(&mut serial, &mut source);
+
+        // Then
+        assert_eq!(Ok(1), result);
+        let (input, message) = parse(&serial.written, Checksum::Crc16).unwrap();
+        let file = FileInfo { name: "seven.bin", size: Some(10), modified: None };
+        match message {
+            Message::Chunk(chunk) => {
+                assert_eq!(0, chunk.block_number);
+                assert_eq!(Some(Header::File(file)), parse_header(chunk.payload));
+            }
+            _ => panic!("Expected a header"),
+        }
+        let (input, message) = parse(input, Checksum::Crc16).unwrap();
+        let mut expected_payload = [xmodem::PADDING; PAYLOAD_SIZE];
+        expected_payload[..10].copy_from_slice(&data);
+        assert_eq!(Message::Chunk(Chunk { block_number: 1, payload: &expected_payload }), message);
+        assert_eq!([xmodem::EOT, xmodem::EOT], input[..2]);
+        let (input, message) = parse(&input[2..], Checksum::Crc16).unwrap();
+        assert_eq!(
+            Message::Chunk(Chunk { block_number: 0, payload: &[0u8; PAYLOAD_SIZE] }),
+            message
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn sender_cancels_files_with_empty_names() {
+        // Given
+        let data = [7u8; 10];
+        let mut source = SliceSource { files: &[("", &data)], current: None };
+        let mut serial = MockSerial::default();
+        serial.to_read.extend([CRC_REQUEST].iter());
+
+        // When
+        let result = Sender::new().send(&mut serial, &mut source);
+
+        // Then
+        assert_eq!(Err(Error::InvalidHeader), result);
+        assert_eq!(vec![xmodem::CAN, xmodem::CAN], serial.written);
+    }
+
+    #[test]
+    fn sender_waits_for_a_crc_request_to_start() {
+        // Given
+        let mut source = SliceSource { files: &[], current: None };
+        let mut serial = MockSerial::default();
+        serial.to_read.extend([NAK, CRC_REQUEST, ACK].iter());
+
+        // When
+        let result = Sender::new().send(&mut serial, &mut source);
+
+        // Then
+        assert_eq!(Ok(0), result);
+        let (input, message) = parse(&serial.written, Checksum::Crc16).unwrap();
+        assert_eq!(
+            Message::Chunk(Chunk { block_number: 0, payload: &[0u8; PAYLOAD_SIZE] }),
+            message
+        );
+        assert!(input.is_empty());
+    }
+}